@@ -0,0 +1,67 @@
+#!/usr/bin/env python3
+"""Regenerate data/oui.txt, the vendor database embedded in the exporter.
+
+Reads the IEEE registries of MAC address blocks (MA-L, MA-M and MA-S) and
+writes one `<assignment><TAB><organization>` line per block, sorted by
+assignment, to keep the embedded file small.
+
+    scripts/update-oui.py                       # download from the IEEE
+    scripts/update-oui.py oui.csv mam.csv ...   # use local copies
+
+Run it (or `make update-oui`) and commit data/oui.txt when the registry has
+changed; the build embeds whatever the file contains.
+"""
+
+import csv
+import io
+import sys
+import urllib.request
+from datetime import datetime, timezone
+from pathlib import Path
+
+REGISTRIES = [
+    "https://standards-oui.ieee.org/oui/oui.csv",
+    "https://standards-oui.ieee.org/oui28/mam.csv",
+    "https://standards-oui.ieee.org/oui36/oui36.csv",
+]
+
+OUTPUT = Path(__file__).resolve().parent.parent / "data" / "oui.txt"
+
+
+def read(source):
+    if source.startswith("https://"):
+        # The IEEE server rejects requests without a browser-like user agent
+        request = urllib.request.Request(source, headers={"User-Agent": "Mozilla/5.0"})
+        with urllib.request.urlopen(request, timeout=120) as response:
+            return response.read().decode("utf-8")
+    return Path(source).read_text(encoding="utf-8")
+
+
+def assignments(text):
+    for row in csv.DictReader(io.StringIO(text)):
+        assignment = row["Assignment"].strip().upper()
+        organization = " ".join(row["Organization Name"].split())
+        if assignment and organization:
+            yield assignment, organization
+
+
+def main():
+    sources = sys.argv[1:] or REGISTRIES
+    blocks = {}
+    for source in sources:
+        print(f"Reading {source}", file=sys.stderr)
+        blocks.update(assignments(read(source)))
+
+    generated = datetime.now(timezone.utc).strftime("%Y-%m-%d")
+    with OUTPUT.open("w", encoding="utf-8", newline="\n") as out:
+        out.write(f"# Generated by scripts/update-oui.py on {generated} from:\n")
+        for source in sources:
+            out.write(f"#   {source}\n")
+        # Sorted by length first, so MA-L blocks come before MA-M and MA-S
+        for assignment in sorted(blocks, key=lambda a: (len(a), a)):
+            out.write(f"{assignment}\t{blocks[assignment]}\n")
+    print(f"Wrote {len(blocks)} assignments to {OUTPUT}", file=sys.stderr)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,467 @@
+use futures_util::future::BoxFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::debug;
+
+use crate::unifi::{
+    Client, ControllerFlavor, Device, ParseErrors, Result, Site, UniFiClient, UniFiError,
+    decode_legacy,
+};
+
+/// A source of controller data.
+///
+/// The poll loop only talks to this interface, so it works the same against
+/// any controller API or recorded responses. Implementations map their data
+/// into the classic API types the metrics are built from.
+pub trait ControllerBackend: Send + Sync {
+    /// Short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// Log in if the backend needs a session.
+    fn authenticate(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Whether the controller rejected the credentials, so retrying is
+    /// pointless.
+    fn credentials_rejected(&self) -> bool {
+        false
+    }
+
+    /// Records skipped since the last call because they failed to decode, by
+    /// endpoint and field.
+    fn take_parse_errors(&self) -> BTreeMap<(String, String), u64> {
+        BTreeMap::new()
+    }
+
+    /// Requests that failed since the last call while the data they were for
+    /// was still returned, by endpoint.
+    fn take_partial_errors(&self) -> Vec<(String, UniFiError)> {
+        Vec::new()
+    }
+
+    fn get_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>>;
+
+    fn get_clients(&self) -> BoxFuture<'_, Result<Vec<Client>>>;
+
+    fn get_sites(&self) -> BoxFuture<'_, Result<Vec<Site>>>;
+
+    /// Release the controller session on shutdown, unless it is kept for the
+    /// next start.
+    fn shutdown(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The classic Network API (`/api/s/<site>/...`), served directly by a
+/// self-hosted Network Application or under `/proxy/network` by UniFi OS.
+pub struct LegacyBackend {
+    client: UniFiClient,
+    name: &'static str,
+}
+
+impl LegacyBackend {
+    /// The controller type is detected on first use, unless the client was
+    /// configured with one.
+    pub fn new(client: UniFiClient) -> Self {
+        Self {
+            client,
+            name: "legacy",
+        }
+    }
+
+    /// A self-hosted Network Application.
+    pub fn classic(client: UniFiClient) -> Self {
+        Self {
+            client: client.with_controller_flavor(ControllerFlavor::Classic),
+            name: "classic",
+        }
+    }
+
+    /// A UniFi OS console proxying the Network API.
+    pub fn unifi_os(client: UniFiClient) -> Self {
+        Self {
+            client: client.with_controller_flavor(ControllerFlavor::UnifiOs),
+            name: "unifi-os",
+        }
+    }
+
+    /// `self/sites` only answers logged-in admins, so with an API key the
+    /// sites are listed through the Integration API instead.
+    pub(crate) async fn sites(client: &UniFiClient) -> Result<Vec<Site>> {
+        if client.uses_api_key() {
+            IntegrationBackend::sites(client).await
+        } else {
+            client.get_legacy_sites().await
+        }
+    }
+}
+
+/// Session handling shared by the backends talking to a live controller.
+async fn shutdown_client(client: &UniFiClient) -> Result<()> {
+    if client.has_session_file() {
+        Ok(())
+    } else {
+        client.logout().await
+    }
+}
+
+impl ControllerBackend for LegacyBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn authenticate(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.client.ensure_authenticated())
+    }
+
+    fn credentials_rejected(&self) -> bool {
+        self.client.credentials_rejected()
+    }
+
+    fn take_parse_errors(&self) -> BTreeMap<(String, String), u64> {
+        self.client.parse_errors().take()
+    }
+
+    fn get_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>> {
+        Box::pin(self.client.get_devices())
+    }
+
+    fn get_clients(&self) -> BoxFuture<'_, Result<Vec<Client>>> {
+        Box::pin(self.client.get_clients())
+    }
+
+    fn get_sites(&self) -> BoxFuture<'_, Result<Vec<Site>>> {
+        Box::pin(Self::sites(&self.client))
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(shutdown_client(&self.client))
+    }
+}
+
+/// The documented Integration API v1 (`/proxy/network/integration/v1`),
+/// authenticated with an API key.
+pub struct IntegrationBackend {
+    client: UniFiClient,
+}
+
+impl IntegrationBackend {
+    pub fn new(client: UniFiClient) -> Self {
+        Self { client }
+    }
+
+    /// Sites, and below devices and clients, are also recorded as the
+    /// classic API responses they stand in for, so captures replay.
+    pub(crate) async fn sites(client: &UniFiClient) -> Result<Vec<Site>> {
+        let sites = client.get_integration_sites().await?;
+        let sites: Vec<Site> = sites.iter().map(|site| site.to_site()).collect();
+        client.record_converted("self/sites", &sites);
+        Ok(sites)
+    }
+}
+
+impl ControllerBackend for IntegrationBackend {
+    fn name(&self) -> &'static str {
+        "integration"
+    }
+
+    fn take_partial_errors(&self) -> Vec<(String, UniFiError)> {
+        self.client.take_partial_errors()
+    }
+
+    fn get_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>> {
+        Box::pin(async {
+            let devices = self.client.get_integration_devices().await?;
+            self.client.record_converted("stat/device", &devices);
+            Ok(devices)
+        })
+    }
+
+    fn get_clients(&self) -> BoxFuture<'_, Result<Vec<Client>>> {
+        Box::pin(async {
+            let clients = self.client.get_integration_clients().await?;
+            self.client.record_converted("stat/sta", &clients);
+            Ok(clients)
+        })
+    }
+
+    fn get_sites(&self) -> BoxFuture<'_, Result<Vec<Site>>> {
+        Box::pin(Self::sites(&self.client))
+    }
+}
+
+/// Serves classic API responses recorded as files instead of asking a
+/// controller.
+///
+/// Each endpoint is read from a file named after it with `/` replaced by `_`
+/// (`stat_device.json`, `stat_sta.json`, `self_sites.json`), or, to simulate
+/// change over time, from a directory of that name (`stat_device/`) holding
+/// timestamped snapshots. Snapshots are served in file name order, one per
+/// request, starting over after the last.
+pub struct ReplayBackend {
+    dir: PathBuf,
+    /// Requests served so far, by endpoint
+    positions: Mutex<HashMap<String, usize>>,
+    parse_errors: ParseErrors,
+}
+
+impl ReplayBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            positions: Mutex::new(HashMap::new()),
+            parse_errors: ParseErrors::default(),
+        }
+    }
+
+    /// The recorded response to serve next for an endpoint.
+    fn next_file(&self, endpoint: &str) -> Result<PathBuf> {
+        let file = self.dir.join(endpoint_file_name(endpoint));
+        if file.is_file() {
+            return Ok(file);
+        }
+
+        let snapshot_dir = file.with_extension("");
+        let mut snapshots: Vec<PathBuf> = std::fs::read_dir(&snapshot_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if snapshots.is_empty() {
+            return Err(UniFiError::InvalidConfig(format!(
+                "No recorded {} response: expected {} or snapshots in {}",
+                endpoint,
+                file.display(),
+                snapshot_dir.display()
+            )));
+        }
+        snapshots.sort();
+
+        let mut positions = self.positions.lock().unwrap();
+        let position = positions.entry(endpoint.to_string()).or_default();
+        let snapshot = snapshots.swap_remove(*position % snapshots.len());
+        *position += 1;
+        Ok(snapshot)
+    }
+
+    /// Replay the recorded response of the endpoint `name`, stored under its
+    /// API path `endpoint`.
+    async fn read<T>(&self, name: &str, endpoint: &str) -> Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let path = self.next_file(endpoint)?;
+        debug!("Replaying {} from {}", endpoint, path.display());
+        let body = tokio::fs::read_to_string(&path).await.map_err(|e| {
+            UniFiError::InvalidConfig(format!("Cannot read {}: {}", path.display(), e))
+        })?;
+        decode_legacy(name, &body, &self.parse_errors)
+    }
+}
+
+/// File name a recorded endpoint response is stored under.
+pub fn endpoint_file_name(endpoint: &str) -> String {
+    format!("{}.json", endpoint.trim_matches('/').replace('/', "_"))
+}
+
+impl ControllerBackend for ReplayBackend {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn take_parse_errors(&self) -> BTreeMap<(String, String), u64> {
+        self.parse_errors.take()
+    }
+
+    fn get_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>> {
+        Box::pin(self.read("stat/device", "stat/device"))
+    }
+
+    fn get_clients(&self) -> BoxFuture<'_, Result<Vec<Client>>> {
+        Box::pin(self.read("stat/sta", "stat/sta"))
+    }
+
+    fn get_sites(&self) -> BoxFuture<'_, Result<Vec<Site>>> {
+        Box::pin(self.read("sites", "self/sites"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const DEVICES_RESPONSE: &str = r#"{"meta":{"rc":"ok"},"data":[{"_id":"d1","mac":"f4:e2:c6:00:00:01","type":"uap","name":"Office AP"}]}"#;
+
+    fn api_key_client(server: &MockServer) -> UniFiClient {
+        UniFiClient::new(
+            server.uri(),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(5),
+            true,
+        )
+        .unwrap()
+    }
+
+    fn replay_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "unifi-exporter-replay-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_endpoint_file_name() {
+        assert_eq!(endpoint_file_name("stat/device"), "stat_device.json");
+        assert_eq!(endpoint_file_name("/self/sites"), "self_sites.json");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_backend_unifi_os() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(DEVICES_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let backend: Box<dyn ControllerBackend> =
+            Box::new(LegacyBackend::unifi_os(api_key_client(&server)));
+        backend.authenticate().await.unwrap();
+        let devices = backend.get_devices().await.unwrap();
+        assert_eq!(devices[0].name.as_deref(), Some("Office AP"));
+        assert!(!backend.credentials_rejected());
+        assert_eq!(backend.name(), "unifi-os");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_backend_classic() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(DEVICES_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let backend = LegacyBackend::classic(api_key_client(&server));
+        assert_eq!(backend.get_devices().await.unwrap().len(), 1);
+        assert_eq!(backend.name(), "classic");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_backend_sites_with_api_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/integration/v1/sites"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"offset":0,"limit":200,"count":1,"totalCount":1,"data":[{"id":"0b4f","internalReference":"default","name":"Default"}]}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let backend = LegacyBackend::unifi_os(api_key_client(&server));
+        let sites = backend.get_sites().await.unwrap();
+        assert_eq!(sites[0].name, "default");
+        assert_eq!(sites[0].desc, "Default");
+    }
+
+    #[tokio::test]
+    async fn test_replay_backend() {
+        let dir = replay_dir("basic");
+        std::fs::write(dir.join("stat_device.json"), DEVICES_RESPONSE).unwrap();
+        std::fs::write(
+            dir.join("self_sites.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"s1","name":"default","desc":"Default"}]}"#,
+        )
+        .unwrap();
+
+        let backend: Box<dyn ControllerBackend> = Box::new(ReplayBackend::new(&dir));
+        assert_eq!(backend.name(), "replay");
+        assert_eq!(backend.get_devices().await.unwrap()[0]._id, "d1");
+        assert_eq!(backend.get_sites().await.unwrap()[0].desc, "Default");
+        // No recording of the endpoint
+        assert_eq!(backend.get_clients().await.unwrap_err().kind(), "config");
+        backend.shutdown().await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn devices_response(name: &str) -> String {
+        format!(
+            r#"{{"meta":{{"rc":"ok"}},"data":[{{"_id":"d1","mac":"f4:e2:c6:00:00:01","type":"uap","name":"{name}"}}]}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_replay_backend_cycles_snapshots() {
+        let dir = replay_dir("snapshots");
+        let snapshots = dir.join("stat_device");
+        std::fs::create_dir(&snapshots).unwrap();
+        std::fs::write(
+            snapshots.join("1700000060.json"),
+            devices_response("second"),
+        )
+        .unwrap();
+        std::fs::write(snapshots.join("1700000000.json"), devices_response("first")).unwrap();
+        std::fs::write(snapshots.join("notes.txt"), "not a snapshot").unwrap();
+
+        let backend = ReplayBackend::new(&dir);
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            let devices = backend.get_devices().await.unwrap();
+            names.push(devices[0].name.clone().unwrap());
+        }
+        assert_eq!(names, ["first", "second", "first"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_backend_file_takes_precedence() {
+        let dir = replay_dir("precedence");
+        std::fs::create_dir(dir.join("stat_device")).unwrap();
+        std::fs::write(
+            dir.join("stat_device").join("1.json"),
+            devices_response("snapshot"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("stat_device.json"), devices_response("fixed")).unwrap();
+
+        let devices = ReplayBackend::new(&dir).get_devices().await.unwrap();
+        assert_eq!(devices[0].name.as_deref(), Some("fixed"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_backend_controller_error() {
+        let dir = replay_dir("error");
+        std::fs::write(
+            dir.join("stat_sta.json"),
+            r#"{"meta":{"rc":"error","msg":"api.err.NoSiteContext"},"data":[]}"#,
+        )
+        .unwrap();
+
+        let err = ReplayBackend::new(&dir).get_clients().await.unwrap_err();
+        assert!(matches!(err, UniFiError::Controller { .. }), "{err:?}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
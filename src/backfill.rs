@@ -0,0 +1,388 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::config::BackfillArgs;
+use crate::unifi::{Client, Device, ReportEntry, UniFiClient};
+
+type Labels = Vec<(&'static str, String)>;
+
+/// A metric family rendered in OpenMetrics text format.
+///
+/// Samples of one series must be in increasing timestamp order and all series
+/// of a family must be contiguous, which `promtool tsdb create-blocks-from
+/// openmetrics` requires.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    counter: bool,
+    series: BTreeMap<Labels, Vec<(i64, f64)>>,
+}
+
+impl Family {
+    fn new(name: &'static str, help: &'static str, counter: bool) -> Self {
+        Self {
+            name,
+            help,
+            counter,
+            series: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, labels: Labels, timestamp_ms: i64, value: f64) {
+        self.series
+            .entry(labels)
+            .or_default()
+            .push((timestamp_ms, value));
+    }
+
+    fn render(&mut self, out: &mut String) {
+        if self.series.is_empty() {
+            return;
+        }
+
+        // OpenMetrics names counter families without the `_total` suffix
+        let family_name = if self.counter {
+            self.name.trim_end_matches("_total")
+        } else {
+            self.name
+        };
+        let kind = if self.counter { "counter" } else { "gauge" };
+        let _ = writeln!(out, "# HELP {family_name} {}", self.help);
+        let _ = writeln!(out, "# TYPE {family_name} {kind}");
+
+        for (labels, samples) in &mut self.series {
+            samples.sort_by_key(|(ts, _)| *ts);
+            samples.dedup_by_key(|(ts, _)| *ts);
+
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let mut cumulative = 0.0;
+            for (ts, value) in samples.iter() {
+                let value = if self.counter {
+                    cumulative += value;
+                    cumulative
+                } else {
+                    *value
+                };
+                let _ = writeln!(
+                    out,
+                    "{}{{{labels}}} {value} {}.{:03}",
+                    self.name,
+                    ts.div_euclid(1000),
+                    ts.rem_euclid(1000)
+                );
+            }
+        }
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render controller report rows as OpenMetrics text.
+///
+/// Report rows carry per-interval deltas; byte counters are accumulated from
+/// zero at the start of the range so `rate()` works on the backfilled data.
+/// Devices and clients are used to fill in the same `id`/`name`/`hostname`
+/// labels the live exporter uses.
+pub fn render(
+    devices: &[Device],
+    clients: &[Client],
+    ap_report: &[ReportEntry],
+    site_report: &[ReportEntry],
+    user_report: &[ReportEntry],
+) -> String {
+    let devices: HashMap<&str, &Device> = devices.iter().map(|d| (d.mac.as_str(), d)).collect();
+    let clients: HashMap<&str, &Client> = clients.iter().map(|c| (c.mac.as_str(), c)).collect();
+
+    let mut device_bytes = Family::new("unifi_device_bytes_total", "Total bytes transferred", true);
+    for entry in ap_report {
+        let Some(mac) = entry.ap.as_deref() else {
+            continue;
+        };
+        let (id, name) = match devices.get(mac) {
+            Some(device) => (
+                device._id.as_str(),
+                device.name.as_deref().unwrap_or("unknown"),
+            ),
+            None => ("", "unknown"),
+        };
+        for (direction, value) in [("tx", entry.tx_bytes), ("rx", entry.rx_bytes)] {
+            if let Some(value) = value {
+                let labels = vec![
+                    ("direction", direction.to_string()),
+                    ("id", id.to_string()),
+                    ("mac", mac.to_string()),
+                    ("name", name.to_string()),
+                ];
+                device_bytes.push(labels, entry.time, value);
+            }
+        }
+    }
+
+    let mut client_bytes = Family::new(
+        "unifi_client_bytes_total",
+        "Total bytes transferred by client",
+        true,
+    );
+    for entry in user_report {
+        let Some(mac) = entry.user.as_deref() else {
+            continue;
+        };
+        let (id, hostname) = match clients.get(mac) {
+            Some(client) => (
+                client._id.as_str(),
+                client.hostname.as_deref().unwrap_or(""),
+            ),
+            None => ("", ""),
+        };
+        for (direction, value) in [("tx", entry.tx_bytes), ("rx", entry.rx_bytes)] {
+            if let Some(value) = value {
+                let labels = vec![
+                    ("direction", direction.to_string()),
+                    ("hostname", hostname.to_string()),
+                    ("id", id.to_string()),
+                    ("mac", mac.to_string()),
+                ];
+                client_bytes.push(labels, entry.time, value);
+            }
+        }
+    }
+
+    let mut clients_total = Family::new("unifi_clients_total", "Total number of clients", false);
+    for entry in site_report {
+        for (client_type, value) in [
+            ("wired", entry.lan_num_sta),
+            ("wireless", entry.wlan_num_sta),
+        ] {
+            if let Some(value) = value {
+                let labels = vec![
+                    ("is_guest", "false".to_string()),
+                    ("network", "all".to_string()),
+                    ("type", client_type.to_string()),
+                ];
+                clients_total.push(labels, entry.time, value);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for family in [&mut device_bytes, &mut client_bytes, &mut clients_total] {
+        family.render(&mut out);
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Pull historical reports from the controller and write them as OpenMetrics.
+pub async fn run(client: &UniFiClient, args: &BackfillArgs) -> Result<()> {
+    client.ensure_authenticated().await?;
+
+    let end = match args.end {
+        Some(end) => end,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+    };
+    let (start_ms, end_ms) = (args.start * 1000, end * 1000);
+
+    let devices = client.get_devices().await?;
+    let clients = client.get_clients().await?;
+    let macs: Vec<String> = clients.iter().map(|c| c.mac.clone()).collect();
+
+    let ap_report = client
+        .get_report(args.resolution, "ap", start_ms, end_ms, &[])
+        .await?;
+    let site_report = client
+        .get_report(args.resolution, "site", start_ms, end_ms, &[])
+        .await?;
+    let user_report = if macs.is_empty() {
+        Vec::new()
+    } else {
+        client
+            .get_report(args.resolution, "user", start_ms, end_ms, &macs)
+            .await?
+    };
+
+    info!(
+        "Fetched {} AP, {} site and {} client report rows",
+        ap_report.len(),
+        site_report.len(),
+        user_report.len()
+    );
+
+    let output = render(&devices, &clients, &ap_report, &site_report, &user_report);
+    match &args.output {
+        Some(path) => std::fs::write(path, output)?,
+        None => print!("{output}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+
+    fn report(time: i64) -> ReportEntry {
+        ReportEntry {
+            time,
+            ap: None,
+            user: None,
+            tx_bytes: None,
+            rx_bytes: None,
+            lan_num_sta: None,
+            wlan_num_sta: None,
+        }
+    }
+
+    fn device() -> Device {
+        Device {
+            _id: "device1".to_string(),
+            name: Some("Office AP".to_string()),
+            mac: "00:11:22:33:44:55".to_string(),
+            device_type: "uap".to_string(),
+            model: None,
+            version: None,
+            adopted: true,
+            state: 1,
+            uptime: None,
+            sys_stats: None,
+            stat: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render_accumulates_counters() {
+        let ap_report = vec![
+            ReportEntry {
+                ap: Some("00:11:22:33:44:55".to_string()),
+                tx_bytes: Some(200.0),
+                ..report(1_700_003_600_000)
+            },
+            ReportEntry {
+                ap: Some("00:11:22:33:44:55".to_string()),
+                tx_bytes: Some(100.0),
+                ..report(1_700_000_000_000)
+            },
+        ];
+
+        let output = render(&[device()], &[], &ap_report, &[], &[]);
+        assert_eq!(
+            output,
+            "# HELP unifi_device_bytes Total bytes transferred\n\
+             # TYPE unifi_device_bytes counter\n\
+             unifi_device_bytes_total{direction=\"tx\",id=\"device1\",mac=\"00:11:22:33:44:55\",name=\"Office AP\"} 100 1700000000.000\n\
+             unifi_device_bytes_total{direction=\"tx\",id=\"device1\",mac=\"00:11:22:33:44:55\",name=\"Office AP\"} 300 1700003600.000\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_render_site_client_counts() {
+        let site_report = vec![ReportEntry {
+            lan_num_sta: Some(4.0),
+            wlan_num_sta: Some(12.0),
+            ..report(1_700_000_000_000)
+        }];
+
+        let output = render(&[], &[], &[], &site_report, &[]);
+        assert!(output.contains("# TYPE unifi_clients_total gauge\n"));
+        assert!(output.contains(
+            r#"unifi_clients_total{is_guest="false",network="all",type="wired"} 4 1700000000.000"#
+        ));
+        assert!(output.contains(
+            r#"unifi_clients_total{is_guest="false",network="all",type="wireless"} 12 1700000000.000"#
+        ));
+    }
+
+    #[test]
+    fn test_render_unknown_client_and_escaping() {
+        let clients = vec![Client {
+            _id: "client1".to_string(),
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            ip: None,
+            hostname: Some("say \"hi\"".to_string()),
+            name: None,
+            network: None,
+            vlan: None,
+            ap_mac: None,
+            signal: None,
+            tx_bytes: None,
+            rx_bytes: None,
+            uptime: None,
+            is_wired: false,
+            is_guest: false,
+            ..Default::default()
+        }];
+        let user_report = vec![
+            ReportEntry {
+                user: Some("aa:bb:cc:dd:ee:ff".to_string()),
+                rx_bytes: Some(10.0),
+                ..report(1_700_000_000_500)
+            },
+            ReportEntry {
+                user: Some("11:11:11:11:11:11".to_string()),
+                rx_bytes: Some(5.0),
+                ..report(1_700_000_000_000)
+            },
+        ];
+
+        let output = render(&[], &clients, &[], &[], &user_report);
+        assert!(output.contains(
+            r#"unifi_client_bytes_total{direction="rx",hostname="say \"hi\"",id="client1",mac="aa:bb:cc:dd:ee:ff"} 10 1700000000.500"#
+        ));
+        assert!(output.contains(
+            r#"unifi_client_bytes_total{direction="rx",hostname="",id="",mac="11:11:11:11:11:11"} 5 1700000000.000"#
+        ));
+    }
+
+    #[test]
+    fn test_render_empty() {
+        assert_eq!(render(&[], &[], &[], &[], &[]), "# EOF\n");
+    }
+
+    #[test]
+    fn test_render_uses_live_metric_names() {
+        // Backfilled series must line up with what the exporter serves live
+        let mut metrics = Metrics::new().unwrap();
+        let mut live_device = device();
+        live_device.stat = Some(crate::unifi::DeviceStats {
+            bytes: None,
+            tx_bytes: Some(1),
+            rx_bytes: Some(1),
+            tx_packets: None,
+            rx_packets: None,
+        });
+        metrics.update_devices(&[live_device]);
+        metrics.update_clients(&[]);
+        let live = metrics.gather();
+
+        let ap_report = vec![ReportEntry {
+            ap: Some("00:11:22:33:44:55".to_string()),
+            tx_bytes: Some(1.0),
+            ..report(0)
+        }];
+        let site_report = vec![ReportEntry {
+            lan_num_sta: Some(1.0),
+            ..report(0)
+        }];
+        let output = render(&[device()], &[], &ap_report, &site_report, &[]);
+
+        for line in output.lines().filter(|l| !l.starts_with('#')) {
+            let series = line.rsplitn(3, ' ').last().unwrap();
+            assert!(live.contains(series), "{series} not exported live");
+        }
+    }
+}
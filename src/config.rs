@@ -1,11 +1,22 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use std::collections::HashMap;
 use std::time::Duration;
 
+use crate::proxy::ProxyOptions;
+use crate::tls::{TlsOptions, parse_fingerprint};
+use crate::unifi::{ControllerFlavor, NetworkApi, ReportInterval};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
     /// UniFi Controller URL (e.g., https://192.168.1.1:8443)
-    #[arg(long, env = "UNIFI_CONTROLLER_URL")]
+    #[arg(
+        long,
+        env = "UNIFI_CONTROLLER_URL",
+        required_unless_present = "replay_dir",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub controller_url: String,
 
     /// UniFi API key (use either API key or username/password)
@@ -24,6 +35,18 @@ pub struct Config {
     #[arg(long, env = "UNIFI_SITE", default_value = "default")]
     pub site: String,
 
+    /// Controller type (classic, unifi-os); auto-detected when not set
+    #[arg(long, env = "UNIFI_CONTROLLER_TYPE", value_enum)]
+    pub controller_type: Option<ControllerFlavor>,
+
+    /// API to read devices and clients from (legacy, integration); integration requires UNIFI_API_KEY
+    #[arg(long, env = "UNIFI_API", value_enum, default_value = "legacy")]
+    pub network_api: NetworkApi,
+
+    /// File to persist the login session in across restarts (username/password auth)
+    #[arg(long, env = "SESSION_FILE")]
+    pub session_file: Option<std::path::PathBuf>,
+
     /// Port to expose metrics on
     #[arg(short, long, env = "METRICS_PORT", default_value = "9897")]
     pub port: u16,
@@ -40,9 +63,149 @@ pub struct Config {
     #[arg(long, env = "HTTP_TIMEOUT", default_value = "10")]
     pub http_timeout: u64,
 
+    /// Timeouts in seconds for individual endpoints, overriding HTTP_TIMEOUT (e.g. stat/sta=30,sites=5)
+    #[arg(long, env = "ENDPOINT_TIMEOUTS", value_delimiter = ',', value_parser = parse_endpoint_timeout)]
+    pub endpoint_timeouts: Vec<(String, u64)>,
+
+    /// Requests sent to the controller at once
+    #[arg(long, env = "MAX_CONCURRENT_REQUESTS", default_value = "4")]
+    pub max_concurrent_requests: usize,
+
+    /// Retries for failed GET requests (timeouts, connection errors, 429, 502-504)
+    #[arg(long, env = "HTTP_RETRIES", default_value = "2")]
+    pub http_retries: u32,
+
+    /// Consecutive failed requests after which the controller is left alone (0 disables)
+    #[arg(long, env = "CIRCUIT_BREAKER_THRESHOLD", default_value = "5")]
+    pub circuit_breaker_threshold: u32,
+
+    /// Seconds to wait before probing a controller after the circuit breaker opened
+    #[arg(long, env = "CIRCUIT_BREAKER_COOLDOWN", default_value = "60")]
+    pub circuit_breaker_cooldown: u64,
+
     /// Verify SSL certificates
     #[arg(long, env = "VERIFY_SSL", default_value = "true")]
     pub verify_ssl: bool,
+
+    /// PEM bundle of CA certificates to trust in addition to the built-in roots
+    #[arg(long, env = "UNIFI_CA_FILE")]
+    pub ca_file: Option<std::path::PathBuf>,
+
+    /// Accept exactly the controller certificate with this SHA-256 fingerprint
+    #[arg(long, env = "UNIFI_PINNED_CERT_SHA256")]
+    pub pinned_cert_sha256: Option<String>,
+
+    /// PEM client certificate for reverse proxies requiring mutual TLS
+    #[arg(long, env = "UNIFI_CLIENT_CERT")]
+    pub client_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of the client certificate (default: read from the certificate file)
+    #[arg(long, env = "UNIFI_CLIENT_KEY")]
+    pub client_key: Option<std::path::PathBuf>,
+
+    /// Host name to verify the certificate against (and send as SNI) when the controller URL is an IP address
+    #[arg(long, env = "UNIFI_TLS_SERVER_NAME")]
+    pub tls_server_name: Option<String>,
+
+    /// Proxy for reaching the controller (http://, https://, socks5:// or socks5h://; default: HTTPS_PROXY/ALL_PROXY)
+    #[arg(long, env = "UNIFI_PROXY")]
+    pub proxy: Option<String>,
+
+    /// Proxy username
+    #[arg(long, env = "UNIFI_PROXY_USERNAME")]
+    pub proxy_username: Option<String>,
+
+    /// Proxy password
+    #[arg(long, env = "UNIFI_PROXY_PASSWORD")]
+    pub proxy_password: Option<String>,
+
+    /// Comma-separated hosts, domains and CIDR ranges to reach without UNIFI_PROXY
+    #[arg(long, env = "UNIFI_NO_PROXY")]
+    pub no_proxy: Option<String>,
+
+    /// Export per-client roam counters (high cardinality)
+    #[arg(long, env = "CLIENT_ROAM_DETAILS", default_value = "false")]
+    pub client_roam_details: bool,
+
+    /// IEEE OUI CSV file (oui.csv, mam.csv or oui36.csv) extending and overriding the bundled vendor database
+    #[arg(long, env = "OUI_FILE")]
+    pub oui_file: Option<std::path::PathBuf>,
+
+    /// Serve metrics from controller responses recorded in this directory instead of a live controller
+    #[arg(long, env = "REPLAY_DIR")]
+    pub replay_dir: Option<std::path::PathBuf>,
+
+    /// Write every controller response to this directory, with MACs, IPs, names and secrets redacted
+    #[arg(long, env = "RECORD_DIR", conflicts_with = "replay_dir")]
+    pub record_dir: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Export historical controller reports as OpenMetrics for `promtool tsdb create-blocks-from openmetrics`
+    Backfill(BackfillArgs),
+    /// Poll the controller once and print the metrics, for troubleshooting or a node_exporter textfile collector
+    Scrape(ScrapeArgs),
+    /// Check connectivity, credentials and permissions and print a diagnosis
+    Doctor,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BackfillArgs {
+    /// Start of the time range (Unix timestamp in seconds)
+    #[arg(long)]
+    pub start: i64,
+
+    /// End of the time range (Unix timestamp in seconds, default: now)
+    #[arg(long)]
+    pub end: Option<i64>,
+
+    /// Report resolution to read from the controller
+    #[arg(long, value_enum, default_value = "hourly")]
+    pub resolution: ReportInterval,
+
+    /// File to write the OpenMetrics output to (default: stdout)
+    #[arg(short, long)]
+    pub output: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ScrapeArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: ScrapeFormat,
+
+    /// File to write the metrics to, replaced atomically (e.g. a `.prom` file in node_exporter's textfile directory; default: stdout)
+    #[arg(short, long)]
+    pub output: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScrapeFormat {
+    /// Prometheus text exposition format, as served on /metrics
+    Text,
+    /// JSON array of metric families
+    Json,
+}
+
+/// Parse an `endpoint=seconds` timeout override.
+fn parse_endpoint_timeout(value: &str) -> Result<(String, u64), String> {
+    let (endpoint, seconds) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected endpoint=seconds, got '{value}'"))?;
+    let seconds = seconds
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid timeout '{seconds}' for endpoint '{endpoint}'"))?;
+    if seconds == 0 {
+        return Err(format!(
+            "timeout for endpoint '{endpoint}' must be greater than 0"
+        ));
+    }
+    Ok((endpoint.trim().to_string(), seconds))
 }
 
 impl Config {
@@ -54,7 +217,38 @@ impl Config {
         Duration::from_secs(self.http_timeout)
     }
 
-    pub fn validate(&self) -> Result<(), String> {
+    pub fn endpoint_timeout_durations(&self) -> HashMap<String, Duration> {
+        self.endpoint_timeouts
+            .iter()
+            .map(|(endpoint, seconds)| (endpoint.clone(), Duration::from_secs(*seconds)))
+            .collect()
+    }
+
+    pub fn circuit_breaker_cooldown_duration(&self) -> Duration {
+        Duration::from_secs(self.circuit_breaker_cooldown)
+    }
+
+    pub fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            ca_file: self.ca_file.clone(),
+            pinned_cert_sha256: self.pinned_cert_sha256.clone(),
+            client_cert: self.client_cert.clone(),
+            client_key: self.client_key.clone(),
+            server_name: self.tls_server_name.clone(),
+        }
+    }
+
+    pub fn proxy_options(&self) -> ProxyOptions {
+        ProxyOptions {
+            url: self.proxy.clone(),
+            username: self.proxy_username.clone(),
+            password: self.proxy_password.clone(),
+            no_proxy: self.no_proxy.clone(),
+        }
+    }
+
+    /// Checks of the controller connection settings.
+    fn validate_controller(&self) -> Result<(), String> {
         // Check that either API key or username/password is provided
         if self.api_key.is_none() && (self.username.is_none() || self.password.is_none()) {
             return Err(
@@ -63,6 +257,10 @@ impl Config {
             );
         }
 
+        if self.network_api == NetworkApi::Integration && self.api_key.is_none() {
+            return Err("UNIFI_API=integration requires UNIFI_API_KEY".to_string());
+        }
+
         // Validate controller URL
         if self.controller_url.is_empty() {
             return Err("UNIFI_CONTROLLER_URL cannot be empty".to_string());
@@ -74,6 +272,18 @@ impl Config {
             return Err("UNIFI_CONTROLLER_URL must start with http:// or https://".to_string());
         }
 
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.replay_dir.is_none() {
+            self.validate_controller()?;
+        } else if let Some(Command::Backfill(_)) = &self.command {
+            return Err("Backfill needs a live controller, not REPLAY_DIR".to_string());
+        } else if let Some(Command::Doctor) = &self.command {
+            return Err("Doctor needs a live controller, not REPLAY_DIR".to_string());
+        }
+
         // Validate poll interval
         if self.poll_interval == 0 {
             return Err("POLL_INTERVAL must be greater than 0".to_string());
@@ -84,6 +294,30 @@ impl Config {
             return Err("HTTP_TIMEOUT must be greater than 0".to_string());
         }
 
+        if self.max_concurrent_requests == 0 {
+            return Err("MAX_CONCURRENT_REQUESTS must be greater than 0".to_string());
+        }
+
+        // Validate TLS options
+        if let Some(pin) = &self.pinned_cert_sha256 {
+            parse_fingerprint(pin).map_err(|e| format!("UNIFI_PINNED_CERT_SHA256: {e}"))?;
+        }
+        if self.client_key.is_some() && self.client_cert.is_none() {
+            return Err("UNIFI_CLIENT_KEY requires UNIFI_CLIENT_CERT".to_string());
+        }
+
+        // Validate proxy options
+        if self.proxy.is_none()
+            && (self.proxy_username.is_some()
+                || self.proxy_password.is_some()
+                || self.no_proxy.is_some())
+        {
+            return Err(
+                "UNIFI_PROXY_USERNAME, UNIFI_PROXY_PASSWORD and UNIFI_NO_PROXY require UNIFI_PROXY"
+                    .to_string(),
+            );
+        }
+
         // Validate port
         if self.port == 0 {
             return Err("METRICS_PORT cannot be 0".to_string());
@@ -98,6 +332,13 @@ impl Config {
             ));
         }
 
+        if let Some(Command::Backfill(args)) = &self.command
+            && let Some(end) = args.end
+            && end <= args.start
+        {
+            return Err("Backfill --end must be after --start".to_string());
+        }
+
         Ok(())
     }
 }
@@ -113,11 +354,33 @@ mod tests {
             username: Some("admin".to_string()),
             password: Some("password".to_string()),
             site: "default".to_string(),
+            controller_type: None,
+            network_api: NetworkApi::Legacy,
+            session_file: None,
             port: 9897,
             poll_interval: 30,
             log_level: "info".to_string(),
             http_timeout: 10,
+            endpoint_timeouts: vec![],
+            max_concurrent_requests: 4,
+            http_retries: 2,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: 60,
             verify_ssl: true,
+            ca_file: None,
+            pinned_cert_sha256: None,
+            client_cert: None,
+            client_key: None,
+            tls_server_name: None,
+            proxy: None,
+            proxy_username: None,
+            proxy_password: None,
+            no_proxy: None,
+            client_roam_details: false,
+            oui_file: None,
+            replay_dir: None,
+            record_dir: None,
+            command: None,
         }
     }
 
@@ -134,6 +397,151 @@ mod tests {
         assert_eq!(config.http_timeout_duration(), Duration::from_secs(15));
     }
 
+    #[test]
+    fn test_replay_dir_without_controller() {
+        let config =
+            Config::try_parse_from(["unifi-network-exporter", "--replay-dir", "captures"]).unwrap();
+        assert_eq!(config.controller_url, "");
+        assert!(config.validate().is_ok());
+
+        // Without a replay directory the controller URL is still required
+        assert!(Config::try_parse_from(["unifi-network-exporter"]).is_err());
+    }
+
+    #[test]
+    fn test_validate_replay_dir_backfill() {
+        let mut config = create_test_config();
+        config.replay_dir = Some("captures".into());
+        config.command = Some(Command::Backfill(BackfillArgs {
+            start: 0,
+            end: None,
+            resolution: ReportInterval::Hourly,
+            output: None,
+        }));
+        assert!(config.validate().unwrap_err().contains("REPLAY_DIR"));
+
+        config.command = Some(Command::Doctor);
+        assert!(config.validate().unwrap_err().contains("REPLAY_DIR"));
+    }
+
+    #[test]
+    fn test_scrape_command() {
+        let config = Config::try_parse_from([
+            "unifi-network-exporter",
+            "--replay-dir",
+            "captures",
+            "scrape",
+            "--format",
+            "json",
+            "-o",
+            "/var/lib/node_exporter/unifi.prom",
+        ])
+        .unwrap();
+        let Some(Command::Scrape(args)) = &config.command else {
+            panic!("expected the scrape command");
+        };
+        assert_eq!(args.format, ScrapeFormat::Json);
+        assert_eq!(
+            args.output.as_deref(),
+            Some(std::path::Path::new("/var/lib/node_exporter/unifi.prom"))
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_timeouts() {
+        let config = Config::try_parse_from([
+            "unifi-network-exporter",
+            "--controller-url",
+            "https://unifi.local",
+            "--api-key",
+            "key",
+            "--endpoint-timeouts",
+            "stat/sta=30, sites=5",
+        ])
+        .unwrap();
+        let timeouts = config.endpoint_timeout_durations();
+        assert_eq!(timeouts["stat/sta"], Duration::from_secs(30));
+        assert_eq!(timeouts["sites"], Duration::from_secs(5));
+        assert_eq!(timeouts.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_endpoint_timeout_invalid() {
+        assert!(parse_endpoint_timeout("stat/sta").is_err());
+        assert!(parse_endpoint_timeout("stat/sta=soon").is_err());
+        assert!(parse_endpoint_timeout("stat/sta=0").is_err());
+    }
+
+    #[test]
+    fn test_validate_tls_options() {
+        let mut config = create_test_config();
+        config.pinned_cert_sha256 = Some("not-a-fingerprint".to_string());
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .starts_with("UNIFI_PINNED_CERT_SHA256")
+        );
+
+        config.pinned_cert_sha256 = Some("ab".repeat(32));
+        assert!(config.validate().is_ok());
+
+        config.client_key = Some("client.key".into());
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "UNIFI_CLIENT_KEY requires UNIFI_CLIENT_CERT"
+        );
+    }
+
+    #[test]
+    fn test_validate_integration_api_requires_api_key() {
+        let mut config = create_test_config();
+        config.network_api = NetworkApi::Integration;
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "UNIFI_API=integration requires UNIFI_API_KEY"
+        );
+
+        config.api_key = Some("key".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_proxy_options() {
+        let mut config = create_test_config();
+        config.no_proxy = Some("localhost".to_string());
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .contains("require UNIFI_PROXY")
+        );
+
+        config.proxy = Some("socks5h://jump:1080".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.proxy_options().no_proxy.as_deref(),
+            Some("localhost")
+        );
+    }
+
+    #[test]
+    fn test_validate_max_concurrent_requests() {
+        let mut config = create_test_config();
+        config.max_concurrent_requests = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker_cooldown_duration() {
+        let config = create_test_config();
+        assert_eq!(
+            config.circuit_breaker_cooldown_duration(),
+            Duration::from_secs(60)
+        );
+    }
+
     #[test]
     fn test_validate_with_api_key() {
         let mut config = create_test_config();
@@ -178,11 +586,33 @@ mod tests {
             username: None,
             password: None,
             site: "default".to_string(),
+            controller_type: None,
+            network_api: NetworkApi::Legacy,
+            session_file: None,
             port: 9897,
             poll_interval: 30,
             log_level: "info".to_string(),
             http_timeout: 10,
+            endpoint_timeouts: vec![],
+            max_concurrent_requests: 4,
+            http_retries: 2,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: 60,
             verify_ssl: true,
+            ca_file: None,
+            pinned_cert_sha256: None,
+            client_cert: None,
+            client_key: None,
+            tls_server_name: None,
+            proxy: None,
+            proxy_username: None,
+            proxy_password: None,
+            no_proxy: None,
+            client_roam_details: false,
+            oui_file: None,
+            replay_dir: None,
+            record_dir: None,
+            command: None,
         };
         assert_eq!(config.site, "default");
         assert_eq!(config.port, 9897);
@@ -190,6 +620,7 @@ mod tests {
         assert_eq!(config.log_level, "info");
         assert_eq!(config.http_timeout, 10);
         assert!(config.verify_ssl);
+        assert!(!config.client_roam_details);
     }
 
     #[test]
@@ -265,6 +696,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_validate_backfill_range() {
+        let mut config = create_test_config();
+        config.command = Some(Command::Backfill(BackfillArgs {
+            start: 1_700_000_000,
+            end: Some(1_700_000_000),
+            resolution: ReportInterval::Hourly,
+            output: None,
+        }));
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "Backfill --end must be after --start"
+        );
+
+        config.command = Some(Command::Backfill(BackfillArgs {
+            start: 1_700_000_000,
+            end: None,
+            resolution: ReportInterval::Daily,
+            output: None,
+        }));
+        assert!(config.validate().is_ok());
+    }
+
     #[test]
     fn test_validate_case_insensitive_log_level() {
         let mut config = create_test_config();
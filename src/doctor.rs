@@ -0,0 +1,840 @@
+use anyhow::{Result, bail};
+use reqwest::Url;
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use crate::backend::{IntegrationBackend, LegacyBackend};
+use crate::config::Config;
+use crate::exporter::build_client;
+use crate::retry::{CircuitBreaker, RetryPolicy};
+use crate::tls;
+use crate::unifi::{ControllerFlavor, NetworkApi, ReportInterval, UniFiClient, UniFiError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Something only some features need is not working
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "skip",
+        }
+    }
+}
+
+/// Result of one diagnostic step.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// HTTP status of the controller's answer, for checks sending a request
+    pub status: Option<u16>,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn push(&mut self, name: &'static str, outcome: Outcome, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            outcome,
+            status: None,
+            detail: detail.into(),
+            hint: None,
+        });
+    }
+
+    /// Record the outcome of a controller request.
+    fn request<T>(
+        &mut self,
+        name: &'static str,
+        result: &Result<T, UniFiError>,
+        detail: impl FnOnce(&T) -> String,
+        hint: impl FnOnce(&UniFiError) -> Option<String>,
+    ) {
+        let check = match result {
+            Ok(value) => Check {
+                name,
+                outcome: Outcome::Pass,
+                status: Some(200),
+                detail: detail(value),
+                hint: None,
+            },
+            Err(e) => Check {
+                name,
+                outcome: Outcome::Fail,
+                status: e.status(),
+                detail: e.to_string(),
+                hint: hint(e),
+            },
+        };
+        self.checks.push(check);
+    }
+
+    fn hint(&mut self, hint: impl Into<String>) {
+        if let Some(check) = self.checks.last_mut() {
+            check.hint = Some(hint.into());
+        }
+    }
+
+    pub fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.outcome == Outcome::Fail)
+            .count()
+    }
+
+    /// The checks as a table, with hints below failed checks.
+    pub fn render(&self) -> String {
+        let name_width = self
+            .checks
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("CHECK".len());
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{:name_width$}  RESULT  HTTP  DETAIL", "CHECK");
+        for check in &self.checks {
+            let status = check
+                .status
+                .map_or_else(|| "-".to_string(), |status| status.to_string());
+            let _ = writeln!(
+                out,
+                "{:name_width$}  {:6}  {:4}  {}",
+                check.name,
+                check.outcome.as_str(),
+                status,
+                check.detail
+            );
+            if let Some(hint) = &check.hint {
+                let _ = writeln!(out, "{:name_width$}  hint: {hint}", "");
+            }
+        }
+        out
+    }
+}
+
+/// Run the diagnostics and print them; fails if any check failed.
+pub async fn run(config: &Config) -> Result<()> {
+    let report = diagnose(config).await;
+    print!("{}", report.render());
+    match report.failed() {
+        0 => Ok(()),
+        failed => bail!("{failed} of {} checks failed", report.checks.len()),
+    }
+}
+
+/// Check each step from the configuration to the endpoints the exporter
+/// polls, stopping where later steps cannot work.
+pub async fn diagnose(config: &Config) -> Report {
+    let mut report = Report::default();
+
+    if let Err(e) = config.validate() {
+        report.push("config", Outcome::Fail, e);
+        return report;
+    }
+    let auth = if config.api_key.is_some() {
+        "API key"
+    } else {
+        "username/password"
+    };
+    let api = match config.network_api {
+        NetworkApi::Legacy => "legacy",
+        NetworkApi::Integration => "integration",
+    };
+    report.push(
+        "config",
+        Outcome::Pass,
+        format!("{auth}, site '{}', {api} API", config.site),
+    );
+
+    let url = match Url::parse(&config.controller_url) {
+        Ok(url) => url,
+        Err(e) => {
+            report.push("dns", Outcome::Fail, format!("Invalid controller URL: {e}"));
+            return report;
+        }
+    };
+    let Ok(addr) = resolve(&mut report, config, &url).await else {
+        return report;
+    };
+    let fingerprint = check_tls(&mut report, config, &url, addr).await;
+
+    let client = match build_client(config) {
+        // Report each failure as it happens instead of retrying or holding
+        // back requests
+        Ok(client) => client
+            .with_retry_policy(RetryPolicy::none())
+            .with_circuit_breaker(CircuitBreaker::disabled()),
+        Err(e) => {
+            report.push("client", Outcome::Fail, e.to_string());
+            return report;
+        }
+    };
+
+    let hints = Hints {
+        config,
+        fingerprint: fingerprint.as_deref(),
+    };
+    if check_controller(&mut report, config, &client, &hints).await {
+        check_endpoints(&mut report, config, &client, &hints).await;
+    }
+
+    if config.api_key.is_none()
+        && !client.has_session_file()
+        && let Err(e) = client.logout().await
+    {
+        warn!("Failed to log out: {}", e);
+    }
+    report
+}
+
+fn host(url: &Url) -> &str {
+    url.host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+/// Resolve the controller host name. Returns the address to probe, if the
+/// exporter connects directly, or `Err` if the name does not resolve.
+async fn resolve(
+    report: &mut Report,
+    config: &Config,
+    url: &Url,
+) -> Result<Option<SocketAddr>, ()> {
+    let host = host(url);
+    let port = url.port_or_known_default().unwrap_or(443);
+    if config.proxy.is_some() {
+        report.push(
+            "dns",
+            Outcome::Skip,
+            format!("{host} is resolved by the proxy"),
+        );
+        return Ok(None);
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        report.push("dns", Outcome::Skip, format!("{ip} is an IP address"));
+        return Ok(Some(SocketAddr::new(ip, port)));
+    }
+
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            let ips: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+            report.push(
+                "dns",
+                Outcome::Pass,
+                format!("{host} -> {}", ips.join(", ")),
+            );
+            Ok(addrs.first().copied())
+        }
+        Err(e) => {
+            report.push(
+                "dns",
+                Outcome::Fail,
+                format!("Failed to resolve {host}: {e}"),
+            );
+            report.hint(
+                "Check the host name in UNIFI_CONTROLLER_URL, or use the controller's IP address",
+            );
+            Err(())
+        }
+    }
+}
+
+/// Read the controller certificate, returning its SHA-256 fingerprint.
+async fn check_tls(
+    report: &mut Report,
+    config: &Config,
+    url: &Url,
+    addr: Option<SocketAddr>,
+) -> Option<String> {
+    if url.scheme() != "https" {
+        report.push("tls", Outcome::Skip, "plain HTTP");
+        return None;
+    }
+    let Some(addr) = addr else {
+        report.push("tls", Outcome::Skip, "connection goes through the proxy");
+        return None;
+    };
+
+    let server_name = config
+        .tls_server_name
+        .clone()
+        .unwrap_or_else(|| host(url).to_string());
+    let timeout = config.http_timeout_duration();
+    let result =
+        tokio::task::spawn_blocking(move || tls::peer_fingerprint(addr, &server_name, timeout))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+
+    match result {
+        Ok(fingerprint) => {
+            let pin_matches = config
+                .pinned_cert_sha256
+                .as_deref()
+                .and_then(|pin| tls::parse_fingerprint(pin).ok())
+                .is_none_or(|pin| tls::format_fingerprint(&pin) == fingerprint);
+            if pin_matches {
+                report.push("tls", Outcome::Pass, format!("SHA-256 {fingerprint}"));
+            } else {
+                report.push("tls", Outcome::Fail, format!("SHA-256 {fingerprint}"));
+                report.hint(
+                    "The certificate does not match UNIFI_PINNED_CERT_SHA256; if the controller's certificate was renewed, pin the new fingerprint",
+                );
+            }
+            Some(fingerprint)
+        }
+        Err(e) => {
+            report.push(
+                "tls",
+                Outcome::Fail,
+                format!("Failed to read the certificate: {e}"),
+            );
+            report.hint(format!(
+                "Check that the controller serves HTTPS on port {}",
+                addr.port()
+            ));
+            None
+        }
+    }
+}
+
+/// Detect the controller type and log in; returns whether the endpoints can
+/// be checked.
+async fn check_controller(
+    report: &mut Report,
+    config: &Config,
+    client: &UniFiClient,
+    hints: &Hints<'_>,
+) -> bool {
+    // The client assumes UniFi OS for API keys, so probe to catch keys used
+    // on classic controllers
+    let detected = if config.api_key.is_some() && config.controller_type.is_none() {
+        client.detect_flavor().await
+    } else {
+        client.controller_flavor().await
+    };
+    report.request(
+        "controller",
+        &detected,
+        |flavor| match flavor {
+            ControllerFlavor::Classic => "classic Network application".to_string(),
+            ControllerFlavor::UnifiOs => "UniFi OS".to_string(),
+        },
+        |e| hints.for_error(e),
+    );
+    let Ok(flavor) = detected else {
+        return false;
+    };
+    if config.api_key.is_some() && flavor == ControllerFlavor::Classic {
+        if let Some(check) = report.checks.last_mut() {
+            check.outcome = Outcome::Fail;
+        }
+        report.hint(API_KEY_NEEDS_UNIFI_OS);
+        return false;
+    }
+
+    if config.api_key.is_some() {
+        // Checked by the first request
+        return true;
+    }
+    let login = client.ensure_authenticated().await;
+    report.request(
+        "login",
+        &login,
+        |_| {
+            format!(
+                "logged in as {}",
+                config.username.as_deref().unwrap_or_default()
+            )
+        },
+        |e| hints.for_error(e),
+    );
+    login.is_ok()
+}
+
+/// Call each endpoint the exporter polls.
+async fn check_endpoints(
+    report: &mut Report,
+    config: &Config,
+    client: &UniFiClient,
+    hints: &Hints<'_>,
+) {
+    let sites = match config.network_api {
+        NetworkApi::Legacy => LegacyBackend::sites(client).await,
+        NetworkApi::Integration => IntegrationBackend::sites(client).await,
+    };
+    if config.api_key.is_some() {
+        match &sites {
+            Err(e @ UniFiError::Unauthorized { .. }) => {
+                report.checks.push(Check {
+                    name: "api key",
+                    outcome: Outcome::Fail,
+                    status: e.status(),
+                    detail: e.to_string(),
+                    hint: hints.for_error(e),
+                });
+                return;
+            }
+            Ok(_) => report.push("api key", Outcome::Pass, "accepted"),
+            // Valid, but without access to the sites; the sites check hints
+            Err(UniFiError::Forbidden { .. }) => {
+                report.push("api key", Outcome::Pass, "accepted, but lacks permission")
+            }
+            // The controller did not answer, so the key was not checked; the
+            // sites check reports the error
+            Err(e) => report.push("api key", Outcome::Skip, format!("not verified: {e}")),
+        }
+    }
+    report.request(
+        "sites",
+        &sites,
+        |sites| {
+            sites
+                .iter()
+                .map(|site| format!("{} ({})", site.name, site.desc))
+                .collect::<Vec<_>>()
+                .join(", ")
+        },
+        |e| hints.for_error(e),
+    );
+    if let Ok(sites) = &sites
+        && !sites.iter().any(|site| site.name == config.site)
+    {
+        if let Some(check) = report.checks.last_mut() {
+            check.outcome = Outcome::Fail;
+        }
+        match sites
+            .iter()
+            .find(|site| site.desc.eq_ignore_ascii_case(&config.site))
+        {
+            Some(site) => report.hint(format!(
+                "UNIFI_SITE is the site's short name, not its description: use '{}' for '{}'",
+                site.name, site.desc
+            )),
+            None => report.hint(format!(
+                "Site '{}' is not visible to this account; UNIFI_SITE must be one of the names listed",
+                config.site
+            )),
+        }
+    }
+
+    match config.network_api {
+        NetworkApi::Legacy => {
+            let (devices, clients) = tokio::join!(client.get_devices(), client.get_clients());
+            report.request(
+                "devices",
+                &devices,
+                |devices| format!("{} from stat/device", devices.len()),
+                |e| hints.for_error(e),
+            );
+            report.request(
+                "clients",
+                &clients,
+                |clients| format!("{} from stat/sta", clients.len()),
+                |e| hints.for_error(e),
+            );
+        }
+        NetworkApi::Integration => {
+            let (devices, clients) = tokio::join!(
+                client.get_integration_devices(),
+                client.get_integration_clients()
+            );
+            report.request(
+                "devices",
+                &devices,
+                |devices| format!("{} from the Integration API", devices.len()),
+                |e| hints.for_error(e),
+            );
+            report.request(
+                "clients",
+                &clients,
+                |clients| format!("{} from the Integration API", clients.len()),
+                |e| hints.for_error(e),
+            );
+        }
+    }
+
+    // Only the backfill command reads reports, so their failure is a warning
+    let end_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+    let start_ms = end_ms - Duration::from_secs(3 * 3600).as_millis() as i64;
+    let reports = client
+        .get_report(ReportInterval::Hourly, "site", start_ms, end_ms, &[])
+        .await;
+    report.request(
+        "reports",
+        &reports,
+        |rows| format!("{} rows from stat/report (backfill)", rows.len()),
+        |e| hints.for_error(e),
+    );
+    if let Some(check) = report.checks.last_mut()
+        && check.outcome == Outcome::Fail
+    {
+        check.outcome = Outcome::Warn;
+        check.hint = Some(format!(
+            "Only the backfill command needs reports{}",
+            check
+                .hint
+                .as_deref()
+                .map(|hint| format!("; {hint}"))
+                .unwrap_or_default()
+        ));
+    }
+}
+
+const API_KEY_NEEDS_UNIFI_OS: &str = "API keys need a UniFi OS console (Network 9.0 or later); classic controllers need UNIFI_USERNAME and UNIFI_PASSWORD";
+
+/// What a failed request most likely means for the user's setup.
+struct Hints<'a> {
+    config: &'a Config,
+    fingerprint: Option<&'a str>,
+}
+
+impl Hints<'_> {
+    fn for_error(&self, error: &UniFiError) -> Option<String> {
+        let site = &self.config.site;
+        let api_key = self.config.api_key.is_some();
+        let hint = match error {
+            UniFiError::Connect { .. } => {
+                "Check that the controller is reachable from here and the port is right: 8443 for classic controllers, 443 for UniFi OS".to_string()
+            }
+            UniFiError::Timeout { .. } => {
+                "The controller did not answer within HTTP_TIMEOUT; check firewalls, or raise HTTP_TIMEOUT for a slow controller".to_string()
+            }
+            UniFiError::Tls { .. } => {
+                let pin = self
+                    .fingerprint
+                    .map(|fingerprint| format!("={fingerprint}"))
+                    .unwrap_or_default();
+                format!(
+                    "The certificate is not trusted: add its CA with UNIFI_CA_FILE, pin it with UNIFI_PINNED_CERT_SHA256{pin}, or set VERIFY_SSL=false"
+                )
+            }
+            UniFiError::AuthenticationFailed => {
+                "Check UNIFI_USERNAME and UNIFI_PASSWORD, and use a local account: UI.com accounts with two-factor authentication cannot log in".to_string()
+            }
+            UniFiError::Unauthorized { .. } if api_key => {
+                "The API key was rejected; create one under Settings > Control Plane > Integrations".to_string()
+            }
+            UniFiError::Unauthorized { .. } => {
+                "The controller rejected the session right after logging in".to_string()
+            }
+            UniFiError::Forbidden { .. } => permission_hint(site),
+            UniFiError::Controller { msg, .. } if msg.contains("NoPermission") => {
+                permission_hint(site)
+            }
+            UniFiError::NotFound { .. } if api_key => API_KEY_NEEDS_UNIFI_OS.to_string(),
+            UniFiError::NotFound { .. } => {
+                "Check UNIFI_SITE and, if set, UNIFI_CONTROLLER_TYPE".to_string()
+            }
+            UniFiError::HttpStatus { status: 400, .. } => unknown_site_hint(site),
+            UniFiError::Controller { msg, .. } if msg.contains("NoSiteContext") => {
+                unknown_site_hint(site)
+            }
+            UniFiError::RateLimited { .. } => {
+                "The controller is rate limiting requests; try again later".to_string()
+            }
+            UniFiError::ServerError { .. } => {
+                "The controller failed to answer; check that the Network application is running"
+                    .to_string()
+            }
+            UniFiError::Decode { .. } => {
+                "The response could not be parsed; please report it with a capture made with --record-dir".to_string()
+            }
+            _ => return None,
+        };
+        Some(hint)
+    }
+}
+
+fn permission_hint(site: &str) -> String {
+    format!("The account lacks permission; give it at least View Only access to site '{site}'")
+}
+
+fn unknown_site_hint(site: &str) -> String {
+    format!(
+        "Site '{site}' does not exist; UNIFI_SITE must be the site's short name (see the sites check)"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const SITES_RESPONSE: &str =
+        r#"{"meta":{"rc":"ok"},"data":[{"_id":"s1","name":"default","desc":"Home"}]}"#;
+    const DEVICES_RESPONSE: &str = r#"{"meta":{"rc":"ok"},"data":[{"_id":"d1","mac":"f4:e2:c6:00:00:01","type":"uap","name":"Office AP"}]}"#;
+    const EMPTY_RESPONSE: &str = r#"{"meta":{"rc":"ok"},"data":[]}"#;
+
+    fn config(server: &MockServer, args: &[&str]) -> Config {
+        let url = server.uri();
+        let mut argv = vec!["unifi-network-exporter", "--controller-url", &url];
+        argv.extend_from_slice(args);
+        argv.push("doctor");
+        Config::try_parse_from(argv).unwrap()
+    }
+
+    fn user_pass() -> [&'static str; 4] {
+        ["--username", "admin", "--password", "secret"]
+    }
+
+    async fn mock(server: &MockServer, verb: &str, route: &str, status: u16, body: &str) {
+        Mock::given(method(verb))
+            .and(path(route))
+            .respond_with(ResponseTemplate::new(status).set_body_string(body))
+            .mount(server)
+            .await;
+    }
+
+    async fn mock_login(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/api/login"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .append_header("Set-Cookie", "unifises=session; Path=/")
+                    .set_body_string(EMPTY_RESPONSE),
+            )
+            .mount(server)
+            .await;
+    }
+
+    /// A classic controller answering every request the exporter makes.
+    async fn classic_controller() -> MockServer {
+        let server = MockServer::start().await;
+        mock(&server, "GET", "/status", 200, EMPTY_RESPONSE).await;
+        mock_login(&server).await;
+        mock(&server, "POST", "/api/logout", 200, EMPTY_RESPONSE).await;
+        mock(&server, "GET", "/api/self/sites", 200, SITES_RESPONSE).await;
+        mock(
+            &server,
+            "GET",
+            "/api/s/default/stat/device",
+            200,
+            DEVICES_RESPONSE,
+        )
+        .await;
+        mock(
+            &server,
+            "GET",
+            "/api/s/default/stat/sta",
+            200,
+            EMPTY_RESPONSE,
+        )
+        .await;
+        mock(
+            &server,
+            "POST",
+            "/api/s/default/stat/report/hourly.site",
+            200,
+            EMPTY_RESPONSE,
+        )
+        .await;
+        server
+    }
+
+    fn check<'a>(report: &'a Report, name: &str) -> &'a Check {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap_or_else(|| panic!("no {name} check in\n{}", report.render()))
+    }
+
+    #[tokio::test]
+    async fn test_healthy_controller() {
+        let server = classic_controller().await;
+        let report = diagnose(&config(&server, &user_pass())).await;
+
+        let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(
+            names,
+            [
+                "config",
+                "dns",
+                "tls",
+                "controller",
+                "login",
+                "sites",
+                "devices",
+                "clients",
+                "reports"
+            ]
+        );
+        assert_eq!(report.failed(), 0, "{}", report.render());
+        assert_eq!(check(&report, "sites").detail, "default (Home)");
+        assert_eq!(check(&report, "devices").detail, "1 from stat/device");
+        assert_eq!(check(&report, "devices").status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn test_site_description_instead_of_name() {
+        let server = MockServer::start().await;
+        mock(
+            &server,
+            "GET",
+            "/api/s/Home/stat/device",
+            400,
+            r#"{"meta":{"rc":"error","msg":"api.err.NoSiteContext"},"data":[]}"#,
+        )
+        .await;
+        mock(&server, "GET", "/status", 200, EMPTY_RESPONSE).await;
+        mock_login(&server).await;
+        mock(&server, "GET", "/api/self/sites", 200, SITES_RESPONSE).await;
+
+        let mut args = user_pass().to_vec();
+        args.extend(["--site", "Home"]);
+        let report = diagnose(&config(&server, &args)).await;
+
+        let sites = check(&report, "sites");
+        assert_eq!(sites.outcome, Outcome::Fail);
+        assert!(
+            sites
+                .hint
+                .as_deref()
+                .unwrap()
+                .contains("use 'default' for 'Home'")
+        );
+        let devices = check(&report, "devices");
+        assert_eq!(devices.outcome, Outcome::Fail);
+        assert!(devices.detail.contains("api.err.NoSiteContext"));
+        assert!(devices.hint.as_deref().unwrap().contains("short name"));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_on_classic_controller() {
+        let server = MockServer::start().await;
+        mock(&server, "GET", "/status", 200, EMPTY_RESPONSE).await;
+
+        let report = diagnose(&config(&server, &["--api-key", "test-api-key"])).await;
+
+        let controller = check(&report, "controller");
+        assert_eq!(controller.outcome, Outcome::Fail);
+        assert_eq!(controller.detail, "classic Network application");
+        assert!(controller.hint.as_deref().unwrap().contains("UniFi OS"));
+        // Nothing else can work
+        assert_eq!(report.checks.last().unwrap().name, "controller");
+    }
+
+    #[tokio::test]
+    async fn test_api_key_not_verified_by_failing_controller() {
+        let server = MockServer::start().await;
+        mock(&server, "GET", "/proxy/network/status", 200, EMPTY_RESPONSE).await;
+        mock(
+            &server,
+            "GET",
+            "/proxy/network/integration/v1/sites",
+            500,
+            "",
+        )
+        .await;
+
+        let report = diagnose(&config(&server, &["--api-key", "test-api-key"])).await;
+
+        let api_key = check(&report, "api key");
+        assert_eq!(api_key.outcome, Outcome::Skip, "{}", report.render());
+        assert!(api_key.detail.starts_with("not verified"));
+        let sites = check(&report, "sites");
+        assert_eq!(sites.outcome, Outcome::Fail);
+        assert_eq!(sites.status, Some(500));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_admin() {
+        let server = MockServer::start().await;
+        mock(&server, "GET", "/api/s/default/stat/sta", 403, "").await;
+        mock(
+            &server,
+            "POST",
+            "/api/s/default/stat/report/hourly.site",
+            403,
+            "",
+        )
+        .await;
+        mock(&server, "GET", "/status", 200, EMPTY_RESPONSE).await;
+        mock_login(&server).await;
+        mock(&server, "GET", "/api/self/sites", 200, SITES_RESPONSE).await;
+        mock(
+            &server,
+            "GET",
+            "/api/s/default/stat/device",
+            200,
+            DEVICES_RESPONSE,
+        )
+        .await;
+
+        let report = diagnose(&config(&server, &user_pass())).await;
+
+        let clients = check(&report, "clients");
+        assert_eq!(clients.outcome, Outcome::Fail);
+        assert_eq!(clients.status, Some(403));
+        assert!(clients.hint.as_deref().unwrap().contains("View Only"));
+        // Only backfill needs reports
+        let reports = check(&report, "reports");
+        assert_eq!(reports.outcome, Outcome::Warn);
+        assert!(
+            reports
+                .hint
+                .as_deref()
+                .unwrap()
+                .starts_with("Only the backfill")
+        );
+        assert_eq!(report.failed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config() {
+        let server = MockServer::start().await;
+        let report = diagnose(&config(&server, &[])).await;
+
+        assert_eq!(report.checks.len(), 1);
+        assert_eq!(report.checks[0].outcome, Outcome::Fail);
+        assert_eq!(server.received_requests().await.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut report = Report::default();
+        report.push("dns", Outcome::Pass, "unifi.lan -> 192.168.1.1");
+        report.checks.push(Check {
+            name: "clients",
+            outcome: Outcome::Fail,
+            status: Some(403),
+            detail: "Forbidden".to_string(),
+            hint: Some("Check permissions".to_string()),
+        });
+
+        assert_eq!(
+            report.render(),
+            "CHECK    RESULT  HTTP  DETAIL\n\
+             dns      pass    -     unifi.lan -> 192.168.1.1\n\
+             clients  FAIL    403   Forbidden\n\
+             \x20        hint: Check permissions\n"
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::Result;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router, routing::get};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+use crate::backend::{ControllerBackend, IntegrationBackend, LegacyBackend};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::record::Recorder;
+use crate::retry::{CircuitBreaker, RetryPolicy};
+use crate::topology::Topology;
+use crate::unifi::{ControllerFlavor, NetworkApi, UniFiClient, UniFiError};
+
+pub type SharedMetrics = Arc<RwLock<Metrics>>;
+pub type SharedTopology = Arc<RwLock<Topology>>;
+
+/// Create the controller client from the configuration.
+pub fn build_client(config: &Config) -> Result<UniFiClient> {
+    let mut client = UniFiClient::new(
+        config.controller_url.clone(),
+        config.api_key.clone(),
+        config.username.clone(),
+        config.password.clone(),
+        config.site.clone(),
+        config.http_timeout_duration(),
+        config.verify_ssl,
+    )?
+    .with_retry_policy(RetryPolicy::new(config.http_retries))
+    .with_circuit_breaker(CircuitBreaker::new(
+        config.circuit_breaker_threshold,
+        config.circuit_breaker_cooldown_duration(),
+    ))
+    .with_max_concurrent_requests(config.max_concurrent_requests)
+    .with_endpoint_timeouts(config.endpoint_timeout_durations())
+    .with_tls(&config.tls_options())?
+    .with_proxy(&config.proxy_options())?;
+    if let Some(flavor) = config.controller_type {
+        client = client.with_controller_flavor(flavor);
+    }
+    if let Some(path) = &config.session_file {
+        client = client.with_session_file(path.clone());
+    }
+    if let Some(dir) = &config.record_dir {
+        info!("Recording controller responses in {}", dir.display());
+        client = client.with_recorder(Recorder::new(dir));
+    }
+    Ok(client)
+}
+
+/// Pick the backend for the configured API and controller type.
+pub fn build_backend(config: &Config, client: UniFiClient) -> Arc<dyn ControllerBackend> {
+    match (config.network_api, config.controller_type) {
+        (NetworkApi::Integration, _) => Arc::new(IntegrationBackend::new(client)),
+        (NetworkApi::Legacy, Some(ControllerFlavor::Classic)) => {
+            Arc::new(LegacyBackend::classic(client))
+        }
+        (NetworkApi::Legacy, Some(ControllerFlavor::UnifiOs)) => {
+            Arc::new(LegacyBackend::unifi_os(client))
+        }
+        (NetworkApi::Legacy, None) => Arc::new(LegacyBackend::new(client)),
+    }
+}
+
+/// HTTP routes serving the metrics and topology.
+pub fn router(metrics: SharedMetrics, topology: SharedTopology) -> Router {
+    Router::new()
+        .route("/", get(root_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/health", get(health_handler))
+        .with_state(metrics)
+        .merge(
+            Router::new()
+                .route("/topology", get(topology_handler))
+                .with_state(topology),
+        )
+}
+
+/// Poll the controller every `poll_interval`, forever.
+pub async fn poll_loop(
+    backend: Arc<dyn ControllerBackend>,
+    metrics: SharedMetrics,
+    topology: SharedTopology,
+    poll_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(poll_interval);
+    // After an overrun, wait a full interval instead of polling back to back
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        info!("Polling UniFi Controller");
+
+        let start = std::time::Instant::now();
+        match poll_unifi_data(backend.as_ref(), &metrics, &topology).await {
+            Ok(_) => info!("Successfully updated metrics"),
+            Err(e) => error!("Failed to poll UniFi data: {}", e),
+        }
+
+        let duration = start.elapsed();
+        if metrics.read().await.record_poll(duration, poll_interval) {
+            warn!(
+                "Poll took {:?}, longer than the poll interval of {:?}",
+                duration, poll_interval
+            );
+        }
+    }
+}
+
+pub async fn poll_unifi_data(
+    backend: &dyn ControllerBackend,
+    metrics: &SharedMetrics,
+    topology: &SharedTopology,
+) -> Result<()> {
+    // Authenticate if needed
+    let login = record_errors(metrics, "login", backend.authenticate()).await;
+    metrics
+        .read()
+        .await
+        .set_auth_failure(backend.credentials_rejected());
+    login?;
+
+    // Fetch data from UniFi; the client bounds how many requests run at once
+    let (devices, clients, sites) = tokio::join!(
+        record_errors(metrics, "stat/device", backend.get_devices()),
+        record_errors(metrics, "stat/sta", backend.get_clients()),
+        record_errors(metrics, "sites", backend.get_sites()),
+    );
+
+    // Records that failed to parse were skipped rather than failing the poll
+    let parse_errors = backend.take_parse_errors();
+    if !parse_errors.is_empty() {
+        let metrics = metrics.read().await;
+        for ((endpoint, field), count) in parse_errors {
+            metrics.record_parse_errors(&endpoint, &field, count);
+        }
+    }
+    // Requests whose data was filled in from other responses, like device
+    // details missing from the device list
+    let partial_errors = backend.take_partial_errors();
+    if !partial_errors.is_empty() {
+        let metrics = metrics.read().await;
+        for (endpoint, e) in partial_errors {
+            metrics.record_error(&endpoint, &e);
+        }
+    }
+    let (devices, clients, sites) = (devices?, clients?, sites?);
+
+    // Update metrics
+    let mut metrics = metrics.write().await;
+    metrics.update_devices(&devices);
+    metrics.update_clients(&clients);
+    metrics.update_sites(&sites);
+    drop(metrics);
+
+    *topology.write().await = Topology::build(&devices, &clients);
+
+    Ok(())
+}
+
+/// Await a controller request, logging and counting its error if it fails.
+async fn record_errors<T>(
+    metrics: &SharedMetrics,
+    endpoint: &str,
+    request: impl std::future::Future<Output = Result<T, UniFiError>>,
+) -> Result<T> {
+    match request.await {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            error!(endpoint, kind = e.kind(), "Request failed: {}", e);
+            metrics.read().await.record_error(endpoint, &e);
+            Err(e.into())
+        }
+    }
+}
+
+async fn root_handler() -> &'static str {
+    "UniFi Network Exporter\n\nEndpoints:\n  /metrics - Prometheus metrics\n  /topology - Network topology (?format=json|dot)\n  /health - Health check\n"
+}
+
+async fn metrics_handler(
+    axum::extract::State(metrics): axum::extract::State<SharedMetrics>,
+) -> String {
+    let metrics = metrics.read().await;
+    metrics.gather()
+}
+
+#[derive(Debug, Deserialize)]
+struct TopologyQuery {
+    format: Option<String>,
+}
+
+/// The network graph as JSON (default) or Graphviz DOT (`?format=dot`).
+///
+/// Switch ports are not nodes of their own: the port a device or wired
+/// client is patched into is the `port` of its link.
+async fn topology_handler(
+    axum::extract::State(topology): axum::extract::State<SharedTopology>,
+    axum::extract::Query(query): axum::extract::Query<TopologyQuery>,
+) -> Response {
+    let topology = topology.read().await;
+    match query.format.as_deref() {
+        Some("json") | None => Json(topology.clone()).into_response(),
+        Some("dot") => (
+            [(header::CONTENT_TYPE, "text/vnd.graphviz")],
+            topology.to_dot(),
+        )
+            .into_response(),
+        Some(format) => (
+            StatusCode::BAD_REQUEST,
+            format!("Unknown topology format '{format}', expected json or dot\n"),
+        )
+            .into_response(),
+    }
+}
+
+async fn health_handler() -> &'static str {
+    "OK"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ReplayBackend;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_root_handler() {
+        let response = root_handler().await;
+        assert!(response.contains("UniFi Network Exporter"));
+        assert!(response.contains("/metrics"));
+        assert!(response.contains("/topology"));
+        assert!(response.contains("/health"));
+    }
+
+    #[tokio::test]
+    async fn test_topology_handler() {
+        let topology = Arc::new(RwLock::new(Topology::default()));
+        let app = Router::new()
+            .route("/topology", get(topology_handler))
+            .with_state(topology);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/topology")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/topology?format=dot")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/vnd.graphviz"
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/topology?format=json")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/topology?format=svg")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_poll_replayed_responses() {
+        let dir = std::env::temp_dir().join(format!("unifi-exporter-poll-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("stat_device.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"d1","mac":"f4:e2:c6:00:00:01","type":"uap","name":"Office AP","state":1,"adopted":true}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("stat_sta.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"c1","mac":"aa:bb:cc:dd:ee:ff","hostname":"laptop","ap_mac":"f4:e2:c6:00:00:01"}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("self_sites.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"s1","name":"default","desc":"Default"}]}"#,
+        )
+        .unwrap();
+
+        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+        let topology = Arc::new(RwLock::new(Topology::default()));
+        poll_unifi_data(&ReplayBackend::new(&dir), &metrics, &topology)
+            .await
+            .unwrap();
+
+        let output = metrics.read().await.gather();
+        assert!(
+            output.contains(
+                r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#
+            )
+        );
+        assert!(output.contains("unifi_clients_total"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_handler() {
+        let response = health_handler().await;
+        assert_eq!(response, "OK");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler() {
+        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+        let response = metrics_handler(axum::extract::State(metrics)).await;
+        // The response should be a valid Prometheus format even if empty
+        assert!(response.is_empty() || response.contains("# HELP") || response.contains("# TYPE"));
+    }
+
+    #[tokio::test]
+    async fn test_router_creation() {
+        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+        let topology = Arc::new(RwLock::new(Topology::default()));
+        let app = router(metrics, topology);
+
+        // Test root endpoint
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test health endpoint
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test metrics endpoint
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test 404
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/nonexistent")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
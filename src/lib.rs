@@ -1,4 +1,15 @@
+pub mod backend;
+pub mod backfill;
 pub mod config;
+pub mod doctor;
+pub mod exporter;
 pub mod metrics;
+pub mod oui;
+pub mod proxy;
+pub mod record;
+pub mod retry;
+pub mod scrape;
+pub mod tls;
+pub mod topology;
 pub mod unifi;
 pub mod unifi_integration;
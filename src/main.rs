@@ -1,66 +1,106 @@
 use anyhow::Result;
-use axum::{Router, routing::get};
 use clap::Parser;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+mod backend;
+mod backfill;
 mod config;
+mod doctor;
+mod exporter;
 mod metrics;
+mod oui;
+mod proxy;
+mod record;
+mod retry;
+mod scrape;
+mod tls;
+mod topology;
 mod unifi;
 mod unifi_integration;
 
-use config::Config;
+use backend::{ControllerBackend, ReplayBackend};
+use config::{Command, Config};
 use metrics::Metrics;
-use unifi::UniFiClient;
-
-type SharedMetrics = Arc<RwLock<Metrics>>;
+use oui::OuiDatabase;
+use topology::Topology;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse configuration
     let config = Config::parse();
 
-    // Validate configuration
-    if let Err(e) = config.validate() {
+    // Validate configuration; doctor reports the error in its diagnosis
+    if let Err(e) = config.validate()
+        && !matches!(config.command, Some(Command::Doctor))
+    {
         eprintln!("Configuration error: {e}");
         std::process::exit(1);
     }
 
-    // Initialize logging
+    // Initialize logging (stderr, so subcommands can write their output to stdout)
     tracing_subscriber::fmt()
         .with_env_filter(&config.log_level)
+        .with_writer(std::io::stderr)
         .init();
 
-    info!("Starting UniFi Network Exporter");
+    if let Some(Command::Doctor) = &config.command {
+        return doctor::run(&config).await;
+    }
 
-    // Create UniFi client
-    let client = UniFiClient::new(
-        config.controller_url.clone(),
-        config.api_key.clone(),
-        config.username.clone(),
-        config.password.clone(),
-        config.site.clone(),
-        config.http_timeout_duration(),
-        config.verify_ssl,
-    )?;
+    let backend: Arc<dyn ControllerBackend> = if let Some(dir) = &config.replay_dir {
+        info!("Replaying controller responses from {}", dir.display());
+        Arc::new(ReplayBackend::new(dir))
+    } else {
+        let client = exporter::build_client(&config)?;
+        if let Some(Command::Backfill(args)) = &config.command {
+            let result = backfill::run(&client, args).await;
+            if !client.has_session_file()
+                && let Err(e) = client.logout().await
+            {
+                warn!("Failed to log out: {}", e);
+            }
+            return result;
+        }
+        exporter::build_backend(&config, client)
+    };
 
     // Initialize metrics
-    let metrics = Arc::new(RwLock::new(Metrics::new()?));
+    let oui = match &config.oui_file {
+        Some(path) => OuiDatabase::with_file(path)?,
+        None => OuiDatabase::embedded(),
+    };
+    let metrics = Arc::new(RwLock::new(
+        Metrics::new()?
+            .with_client_roam_details(config.client_roam_details)
+            .with_oui_database(oui),
+    ));
+
+    if let Some(Command::Scrape(args)) = &config.command {
+        let result = scrape::run(backend.as_ref(), &metrics, args).await;
+        if let Err(e) = backend.shutdown().await {
+            warn!("Failed to log out: {}", e);
+        }
+        return result;
+    }
+
+    info!(
+        "Starting UniFi Network Exporter ({} backend)",
+        backend.name()
+    );
+
+    let topology = Arc::new(RwLock::new(Topology::default()));
 
     // Create HTTP server for metrics
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/metrics", get(metrics_handler))
-        .route("/health", get(health_handler))
-        .with_state(metrics.clone());
+    let app = exporter::router(metrics.clone(), topology.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
     info!("Metrics server listening on {}", addr);
 
     // Spawn metrics server
-    let server = tokio::spawn(async move {
+    let mut server = tokio::spawn(async move {
         if let Err(e) = axum::serve(
             tokio::net::TcpListener::bind(addr).await.unwrap(),
             app.into_make_service(),
@@ -72,159 +112,61 @@ async fn main() -> Result<()> {
     });
 
     // Start polling loop in a separate task
-    let poll_metrics = metrics.clone();
-    let poll_handle = tokio::spawn(async move {
-        let poll_interval = config.poll_interval_duration();
-        let mut interval = tokio::time::interval(poll_interval);
-
-        loop {
-            interval.tick().await;
-
-            info!("Polling UniFi Controller");
-
-            match poll_unifi_data(&client, &poll_metrics).await {
-                Ok(_) => info!("Successfully updated metrics"),
-                Err(e) => error!("Failed to poll UniFi data: {}", e),
-            }
-        }
-    });
-
-    // Wait for both tasks
+    let mut poll_handle = tokio::spawn(exporter::poll_loop(
+        backend.clone(),
+        metrics,
+        topology,
+        config.poll_interval_duration(),
+    ));
+
+    // Wait for both tasks or a shutdown signal
     tokio::select! {
-        _ = server => error!("Server task ended unexpectedly"),
-        _ = poll_handle => error!("Polling task ended unexpectedly"),
+        _ = &mut server => error!("Server task ended unexpectedly"),
+        _ = &mut poll_handle => error!("Polling task ended unexpectedly"),
+        _ = shutdown_signal() => info!("Shutting down"),
     }
+    poll_handle.abort();
+    server.abort();
 
-    Ok(())
-}
-
-async fn poll_unifi_data(client: &UniFiClient, metrics: &SharedMetrics) -> Result<()> {
-    // Authenticate if needed
-    client.ensure_authenticated().await?;
-
-    // Fetch data from UniFi
-    let devices = client.get_devices().await?;
-    let clients = client.get_clients().await?;
-    let sites = client.get_sites().await?;
-
-    // Update metrics
-    let mut metrics = metrics.write().await;
-    metrics.update_devices(&devices);
-    metrics.update_clients(&clients);
-    metrics.update_sites(&sites);
+    // Don't leave a session behind on the controller, unless it is cached
+    // for the next start
+    if let Err(e) = backend.shutdown().await {
+        warn!("Failed to log out: {}", e);
+    }
 
     Ok(())
 }
 
-async fn root_handler() -> &'static str {
-    "UniFi Network Exporter\n\nEndpoints:\n  /metrics - Prometheus metrics\n  /health - Health check\n"
-}
-
-async fn metrics_handler(
-    axum::extract::State(metrics): axum::extract::State<SharedMetrics>,
-) -> String {
-    let metrics = metrics.read().await;
-    metrics.gather()
-}
+/// Resolve on Ctrl-C or, on Unix, SIGTERM (`docker stop`).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-async fn health_handler() -> &'static str {
-    "OK"
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use axum::http::Request;
-    use axum::http::StatusCode;
-    use tower::ServiceExt;
-
     #[test]
     fn test_main_components() {
         // Test that main components are properly defined
         // This is a placeholder test - it just verifies the module compiles
         assert_eq!(1 + 1, 2);
     }
-
-    #[tokio::test]
-    async fn test_root_handler() {
-        let response = root_handler().await;
-        assert!(response.contains("UniFi Network Exporter"));
-        assert!(response.contains("/metrics"));
-        assert!(response.contains("/health"));
-    }
-
-    #[tokio::test]
-    async fn test_health_handler() {
-        let response = health_handler().await;
-        assert_eq!(response, "OK");
-    }
-
-    #[tokio::test]
-    async fn test_metrics_handler() {
-        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
-        let response = metrics_handler(axum::extract::State(metrics)).await;
-        // The response should be a valid Prometheus format even if empty
-        assert!(response.is_empty() || response.contains("# HELP") || response.contains("# TYPE"));
-    }
-
-    #[tokio::test]
-    async fn test_router_creation() {
-        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
-        let app = Router::new()
-            .route("/", get(root_handler))
-            .route("/metrics", get(metrics_handler))
-            .route("/health", get(health_handler))
-            .with_state(metrics.clone());
-
-        // Test root endpoint
-        let response = app
-            .clone()
-            .oneshot(
-                Request::builder()
-                    .uri("/")
-                    .body(axum::body::Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-
-        // Test health endpoint
-        let response = app
-            .clone()
-            .oneshot(
-                Request::builder()
-                    .uri("/health")
-                    .body(axum::body::Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-
-        // Test metrics endpoint
-        let response = app
-            .clone()
-            .oneshot(
-                Request::builder()
-                    .uri("/metrics")
-                    .body(axum::body::Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-
-        // Test 404
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/nonexistent")
-                    .body(axum::body::Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
-    }
 }
@@ -1,7 +1,41 @@
 use anyhow::Result;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prometheus::proto::MetricType;
 use prometheus::{Encoder, GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde_json::{Map, Value, json};
+
+use crate::oui::OuiDatabase;
+use crate::unifi::{Client, Device, Site, UniFiError};
+
+/// Signal below which a client counts as poorly connected
+const STICKY_SIGNAL_DBM: i32 = -75;
+/// How much better a client's signal on another AP must have been
+const STICKY_MARGIN_DB: i32 = 10;
+/// Consecutive polls a client must stay on the same AP before it is sticky
+const STICKY_MIN_POLLS: u32 = 3;
+/// Polls a signal seen on an AP is remembered for after the client left it
+const STICKY_HISTORY_POLLS: u64 = 20;
+
+/// Wireless client state carried across polls for roaming detection.
+#[derive(Debug, Clone)]
+struct ClientState {
+    ap_mac: String,
+    polls_on_ap: u32,
+    /// Polls the client has been seen in
+    polls: u64,
+    /// Best signal seen for this client on each AP it has been associated to
+    /// in the last `STICKY_HISTORY_POLLS` polls
+    best_signal_by_ap: HashMap<String, ApSignal>,
+}
 
-use crate::unifi::{Client, Device, Site};
+#[derive(Debug, Clone, Copy)]
+struct ApSignal {
+    best: i32,
+    /// `ClientState::polls` when the client was last seen on the AP
+    seen_at: u64,
+}
 
 pub struct Metrics {
     registry: Registry,
@@ -15,6 +49,16 @@ pub struct Metrics {
     device_memory_total: IntGaugeVec,
     device_bytes_total: IntCounterVec,
     device_packets_total: IntCounterVec,
+    device_upgradable: IntGaugeVec,
+    device_upgrade_state: IntGaugeVec,
+    device_upgrade_info: IntGaugeVec,
+    device_firmware_versions: IntGaugeVec,
+    device_uplink_info: IntGaugeVec,
+    device_uplink_speed: IntGaugeVec,
+    device_uplink_full_duplex: IntGaugeVec,
+    device_uplink_signal: IntGaugeVec,
+    device_uplink_depth: IntGaugeVec,
+    lldp_neighbor_info: IntGaugeVec,
 
     // Client metrics
     client_info: IntGaugeVec,
@@ -22,9 +66,26 @@ pub struct Metrics {
     client_signal_strength: IntGaugeVec,
     client_uptime: IntGaugeVec,
     clients_total: IntGaugeVec,
+    clients_by_vendor: IntGaugeVec,
+    oui: OuiDatabase,
+
+    // Roaming metrics
+    client_roams_total: IntCounterVec,
+    ap_roams_total: IntCounterVec,
+    client_roam_events_total: IntCounterVec,
+    client_sticky: IntGaugeVec,
+    client_roam_details: bool,
+    client_states: HashMap<String, ClientState>,
 
     // Site metrics
     sites_total: IntGaugeVec,
+
+    // Exporter self-metrics
+    exporter_errors_total: IntCounterVec,
+    exporter_parse_errors_total: IntCounterVec,
+    auth_failure: IntGaugeVec,
+    poll_duration_seconds: GaugeVec,
+    poll_overruns_total: IntCounterVec,
 }
 
 impl Metrics {
@@ -95,10 +156,118 @@ impl Metrics {
         )?;
         registry.register(Box::new(device_packets_total.clone()))?;
 
+        let device_upgradable = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_upgradable",
+                "Firmware upgrade available (1=upgradable, 0=up to date)",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_upgradable.clone()))?;
+
+        let device_upgrade_state = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_upgrade_state",
+                "Firmware upgrade state reported by the controller",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_upgrade_state.clone()))?;
+
+        let device_upgrade_info = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_upgrade_info",
+                "Firmware version a device can be upgraded to",
+            ),
+            &[
+                "id",
+                "name",
+                "mac",
+                "version",
+                "target_version",
+                "required_version",
+            ],
+        )?;
+        registry.register(Box::new(device_upgrade_info.clone()))?;
+
+        let device_firmware_versions = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_firmware_versions",
+                "Number of devices per model running each firmware version",
+            ),
+            &["model", "version"],
+        )?;
+        registry.register(Box::new(device_firmware_versions.clone()))?;
+
+        let device_uplink_info = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_uplink_info",
+                "Device uplink to its parent device (type: wire, wireless, mesh)",
+            ),
+            &["id", "name", "mac", "parent_mac", "parent_port", "type"],
+        )?;
+        registry.register(Box::new(device_uplink_info.clone()))?;
+
+        let device_uplink_speed = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_uplink_speed_mbps",
+                "Device uplink link speed in Mbps",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_uplink_speed.clone()))?;
+
+        let device_uplink_full_duplex = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_uplink_full_duplex",
+                "Device uplink duplex (1=full duplex, 0=half duplex)",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_uplink_full_duplex.clone()))?;
+
+        let device_uplink_signal = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_uplink_signal_dbm",
+                "Wireless (mesh) uplink signal strength in dBm",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_uplink_signal.clone()))?;
+
+        let device_uplink_depth = IntGaugeVec::new(
+            Opts::new(
+                "unifi_device_uplink_depth",
+                "Number of hops between the device and the gateway",
+            ),
+            &["id", "name", "mac"],
+        )?;
+        registry.register(Box::new(device_uplink_depth.clone()))?;
+
+        let lldp_neighbor_info = IntGaugeVec::new(
+            Opts::new(
+                "unifi_lldp_neighbor_info",
+                "Neighbor discovered through LLDP on a device port",
+            ),
+            &[
+                "id",
+                "name",
+                "mac",
+                "local_port",
+                "chassis_id",
+                "port_id",
+                "system_name",
+                "vendor",
+            ],
+        )?;
+        registry.register(Box::new(lldp_neighbor_info.clone()))?;
+
         // Client metrics
         let client_info = IntGaugeVec::new(
             Opts::new("unifi_client_info", "UniFi client information"),
-            &["id", "mac", "hostname", "name", "ip", "network", "ap_mac"],
+            &[
+                "id", "mac", "hostname", "name", "ip", "network", "ap_mac", "vendor",
+            ],
         )?;
         registry.register(Box::new(client_info.clone()))?;
 
@@ -135,11 +304,103 @@ impl Metrics {
         )?;
         registry.register(Box::new(clients_total.clone()))?;
 
+        let clients_by_vendor = IntGaugeVec::new(
+            Opts::new(
+                "unifi_clients_by_vendor_total",
+                "Number of clients per MAC address vendor",
+            ),
+            &["vendor"],
+        )?;
+        registry.register(Box::new(clients_by_vendor.clone()))?;
+
+        // Roaming metrics
+        let client_roams_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_client_roams_total",
+                "Wireless client roams between access points",
+            ),
+            &["from_ap", "to_ap"],
+        )?;
+        registry.register(Box::new(client_roams_total.clone()))?;
+
+        let ap_roams_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_ap_roams_total",
+                "Wireless client roams into and out of an access point",
+            ),
+            &["ap_mac", "direction"],
+        )?;
+        registry.register(Box::new(ap_roams_total.clone()))?;
+
+        let client_roam_events_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_client_roam_events_total",
+                "Roams per wireless client (only with CLIENT_ROAM_DETAILS)",
+            ),
+            &["id", "mac", "hostname"],
+        )?;
+        registry.register(Box::new(client_roam_events_total.clone()))?;
+
+        let client_sticky = IntGaugeVec::new(
+            Opts::new(
+                "unifi_client_sticky",
+                "Client stuck on an AP with poor signal while it had a better AP before",
+            ),
+            &["id", "mac", "hostname", "ap_mac"],
+        )?;
+        registry.register(Box::new(client_sticky.clone()))?;
+
         // Site metrics
         let sites_total =
             IntGaugeVec::new(Opts::new("unifi_sites_total", "Total number of sites"), &[])?;
         registry.register(Box::new(sites_total.clone()))?;
 
+        // Exporter self-metrics
+        let exporter_errors_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_exporter_errors_total",
+                "Errors talking to the UniFi controller by endpoint and kind",
+            ),
+            &["endpoint", "kind"],
+        )?;
+        registry.register(Box::new(exporter_errors_total.clone()))?;
+
+        let exporter_parse_errors_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_exporter_parse_errors_total",
+                "Controller records skipped because a field failed to parse, by endpoint and field",
+            ),
+            &["endpoint", "field"],
+        )?;
+        registry.register(Box::new(exporter_parse_errors_total.clone()))?;
+
+        let auth_failure = IntGaugeVec::new(
+            Opts::new(
+                "unifi_auth_failure",
+                "Whether the controller rejected the configured credentials (1=rejected)",
+            ),
+            &[],
+        )?;
+        registry.register(Box::new(auth_failure.clone()))?;
+
+        let poll_duration_seconds = GaugeVec::new(
+            Opts::new(
+                "unifi_exporter_poll_duration_seconds",
+                "Duration of the last poll of the controller in seconds",
+            ),
+            &[],
+        )?;
+        registry.register(Box::new(poll_duration_seconds.clone()))?;
+
+        let poll_overruns_total = IntCounterVec::new(
+            Opts::new(
+                "unifi_exporter_poll_overruns_total",
+                "Polls that took longer than the poll interval",
+            ),
+            &[],
+        )?;
+        registry.register(Box::new(poll_overruns_total.clone()))?;
+
         Ok(Self {
             registry,
             device_info,
@@ -151,15 +412,50 @@ impl Metrics {
             device_memory_total,
             device_bytes_total,
             device_packets_total,
+            device_upgradable,
+            device_upgrade_state,
+            device_upgrade_info,
+            device_firmware_versions,
+            device_uplink_info,
+            device_uplink_speed,
+            device_uplink_full_duplex,
+            device_uplink_signal,
+            device_uplink_depth,
+            lldp_neighbor_info,
             client_info,
             client_bytes_total,
             client_signal_strength,
             client_uptime,
             clients_total,
+            clients_by_vendor,
+            oui: OuiDatabase::embedded(),
+            client_roams_total,
+            ap_roams_total,
+            client_roam_events_total,
+            client_sticky,
+            client_roam_details: false,
+            client_states: HashMap::new(),
             sites_total,
+            exporter_errors_total,
+            exporter_parse_errors_total,
+            auth_failure,
+            poll_duration_seconds,
+            poll_overruns_total,
         })
     }
 
+    /// Use a custom OUI database for vendor labels.
+    pub fn with_oui_database(mut self, oui: OuiDatabase) -> Self {
+        self.oui = oui;
+        self
+    }
+
+    /// Also export roam counters per client (`unifi_client_roam_events_total`).
+    pub fn with_client_roam_details(mut self, enabled: bool) -> Self {
+        self.client_roam_details = enabled;
+        self
+    }
+
     pub fn update_devices(&mut self, devices: &[Device]) {
         // Clear existing metrics
         self.device_info.reset();
@@ -169,6 +465,18 @@ impl Metrics {
         self.device_cpu_usage.reset();
         self.device_memory_usage.reset();
         self.device_memory_total.reset();
+        self.device_upgradable.reset();
+        self.device_upgrade_state.reset();
+        self.device_upgrade_info.reset();
+        self.device_firmware_versions.reset();
+        self.device_uplink_info.reset();
+        self.device_uplink_speed.reset();
+        self.device_uplink_full_duplex.reset();
+        self.device_uplink_signal.reset();
+        self.device_uplink_depth.reset();
+        self.lldp_neighbor_info.reset();
+
+        let mut firmware_counts: HashMap<(String, String), i64> = HashMap::new();
 
         for device in devices {
             let name = device.name.as_deref().unwrap_or("unknown");
@@ -211,6 +519,121 @@ impl Metrics {
                 .with_label_values(&state_refs)
                 .set(device.state as i64);
 
+            // Firmware
+            *firmware_counts
+                .entry((model.to_string(), version.to_string()))
+                .or_insert(0) += 1;
+
+            let upgradable_labels = [device._id.clone(), name.to_string(), device.mac.clone()];
+            let upgradable_refs: Vec<&str> = upgradable_labels.iter().map(|s| s.as_str()).collect();
+            self.device_upgradable
+                .with_label_values(&upgradable_refs)
+                .set(if device.upgradable { 1 } else { 0 });
+
+            if let Some(upgrade_state) = device.upgrade_state {
+                let upgrade_state_labels =
+                    [device._id.clone(), name.to_string(), device.mac.clone()];
+                let upgrade_state_refs: Vec<&str> =
+                    upgrade_state_labels.iter().map(|s| s.as_str()).collect();
+                self.device_upgrade_state
+                    .with_label_values(&upgrade_state_refs)
+                    .set(upgrade_state as i64);
+            }
+
+            if device.upgradable {
+                let upgrade_info_labels = [
+                    device._id.clone(),
+                    name.to_string(),
+                    device.mac.clone(),
+                    version.to_string(),
+                    device
+                        .upgrade_to_firmware
+                        .as_deref()
+                        .unwrap_or("unknown")
+                        .to_string(),
+                    device.required_version.as_deref().unwrap_or("").to_string(),
+                ];
+                let upgrade_info_refs: Vec<&str> =
+                    upgrade_info_labels.iter().map(|s| s.as_str()).collect();
+                self.device_upgrade_info
+                    .with_label_values(&upgrade_info_refs)
+                    .set(1);
+            }
+
+            // Uplink (fall back to the last known uplink while a device is offline)
+            if let Some(uplink) = device.uplink.as_ref().or(device.last_uplink.as_ref()) {
+                let uplink_type = uplink.kind(&device.device_type);
+                let uplink_info_labels = [
+                    device._id.clone(),
+                    name.to_string(),
+                    device.mac.clone(),
+                    uplink.uplink_mac.clone().unwrap_or_default(),
+                    uplink
+                        .uplink_remote_port
+                        .map(|port| port.to_string())
+                        .unwrap_or_default(),
+                    uplink_type.to_string(),
+                ];
+                let uplink_info_refs: Vec<&str> =
+                    uplink_info_labels.iter().map(|s| s.as_str()).collect();
+                self.device_uplink_info
+                    .with_label_values(&uplink_info_refs)
+                    .set(1);
+            }
+            if let Some(uplink) = &device.uplink {
+                let uplink_labels = [device._id.clone(), name.to_string(), device.mac.clone()];
+                let uplink_refs: Vec<&str> = uplink_labels.iter().map(|s| s.as_str()).collect();
+                if let Some(speed) = uplink.speed {
+                    self.device_uplink_speed
+                        .with_label_values(&uplink_refs)
+                        .set(speed);
+                }
+                if let Some(full_duplex) = uplink.full_duplex {
+                    self.device_uplink_full_duplex
+                        .with_label_values(&uplink_refs)
+                        .set(if full_duplex { 1 } else { 0 });
+                }
+                if uplink.kind(&device.device_type) != "wire"
+                    && let Some(signal) = uplink.signal
+                {
+                    self.device_uplink_signal
+                        .with_label_values(&uplink_refs)
+                        .set(signal as i64);
+                }
+            }
+            if let Some(depth) = device.uplink_depth {
+                let depth_labels = [device._id.clone(), name.to_string(), device.mac.clone()];
+                let depth_refs: Vec<&str> = depth_labels.iter().map(|s| s.as_str()).collect();
+                self.device_uplink_depth
+                    .with_label_values(&depth_refs)
+                    .set(depth as i64);
+            }
+
+            // LLDP neighbors
+            for neighbor in &device.lldp_table {
+                let local_port = neighbor
+                    .local_port_name
+                    .clone()
+                    .or_else(|| neighbor.local_port_idx.map(|idx| idx.to_string()))
+                    .unwrap_or_default();
+                let lldp_labels = [
+                    device._id.clone(),
+                    name.to_string(),
+                    device.mac.clone(),
+                    local_port,
+                    neighbor.chassis_id.clone().unwrap_or_default(),
+                    neighbor.port_id.clone().unwrap_or_default(),
+                    neighbor.system_name.clone().unwrap_or_default(),
+                    neighbor
+                        .chassis_id
+                        .as_deref()
+                        .map(|chassis_id| self.oui.vendor(chassis_id).to_string())
+                        .unwrap_or_default(),
+                ];
+                let lldp_refs: Vec<&str> = lldp_labels.iter().map(|s| s.as_str()).collect();
+                self.lldp_neighbor_info.with_label_values(&lldp_refs).set(1);
+            }
+
             // System stats
             if let Some(sys_stats) = &device.sys_stats {
                 if let Some(load1) = sys_stats.loadavg_1 {
@@ -328,6 +751,12 @@ impl Metrics {
                 }
             }
         }
+
+        for ((model, version), count) in firmware_counts {
+            self.device_firmware_versions
+                .with_label_values(&[model.as_str(), version.as_str()])
+                .set(count);
+        }
     }
 
     pub fn update_clients(&mut self, clients: &[Client]) {
@@ -336,13 +765,17 @@ impl Metrics {
         self.client_signal_strength.reset();
         self.client_uptime.reset();
         self.clients_total.reset();
+        self.clients_by_vendor.reset();
+        self.client_sticky.reset();
+
+        let mut client_states = HashMap::new();
 
         // Count clients by type
         let mut wired_count = 0;
         let mut wireless_count = 0;
         let mut guest_count = 0;
-        let mut network_counts: std::collections::HashMap<String, i64> =
-            std::collections::HashMap::new();
+        let mut network_counts: HashMap<String, i64> = HashMap::new();
+        let mut vendor_counts: HashMap<String, i64> = HashMap::new();
 
         for client in clients {
             let hostname = client.hostname.as_deref().unwrap_or("");
@@ -350,6 +783,7 @@ impl Metrics {
             let ip = client.ip.as_deref().unwrap_or("");
             let network = client.network.as_deref().unwrap_or("unknown");
             let ap_mac = client.ap_mac.as_deref().unwrap_or("");
+            let vendor = self.oui.vendor(&client.mac).to_string();
 
             // Client info
             let client_info_labels = [
@@ -360,6 +794,7 @@ impl Metrics {
                 ip.to_string(),
                 network.to_string(),
                 ap_mac.to_string(),
+                vendor.clone(),
             ];
             let client_info_refs: Vec<&str> =
                 client_info_labels.iter().map(|s| s.as_str()).collect();
@@ -411,6 +846,14 @@ impl Metrics {
                     .set(uptime);
             }
 
+            // Roaming
+            if !client.is_wired
+                && let Some(ap_mac) = client.ap_mac.as_deref()
+            {
+                let state = self.track_client(client, ap_mac, hostname);
+                client_states.insert(client.mac.clone(), state);
+            }
+
             // Count clients
             if client.is_wired {
                 wired_count += 1;
@@ -421,8 +864,12 @@ impl Metrics {
                 guest_count += 1;
             }
             *network_counts.entry(network.to_string()).or_insert(0) += 1;
+            *vendor_counts.entry(vendor).or_insert(0) += 1;
         }
 
+        // Clients that disappeared lose their roaming state
+        self.client_states = client_states;
+
         // Update totals
         let wired_labels = ["wired".to_string(), "all".to_string(), "false".to_string()];
         let wired_refs: Vec<&str> = wired_labels.iter().map(|s| s.as_str()).collect();
@@ -457,6 +904,89 @@ impl Metrics {
                 .with_label_values(&network_refs)
                 .set(count);
         }
+
+        // Per-vendor counts
+        for (vendor, count) in vendor_counts {
+            self.clients_by_vendor
+                .with_label_values(&[vendor.as_str()])
+                .set(count);
+        }
+    }
+
+    /// Update the roaming state of a wireless client and record roams and
+    /// stickiness.
+    fn track_client(&mut self, client: &Client, ap_mac: &str, hostname: &str) -> ClientState {
+        let mut state = match self.client_states.remove(&client.mac) {
+            Some(previous) if previous.ap_mac != ap_mac => {
+                self.client_roams_total
+                    .with_label_values(&[previous.ap_mac.as_str(), ap_mac])
+                    .inc();
+                self.ap_roams_total
+                    .with_label_values(&[previous.ap_mac.as_str(), "out"])
+                    .inc();
+                self.ap_roams_total.with_label_values(&[ap_mac, "in"]).inc();
+                if self.client_roam_details {
+                    self.client_roam_events_total
+                        .with_label_values(&[client._id.as_str(), client.mac.as_str(), hostname])
+                        .inc();
+                }
+                ClientState {
+                    ap_mac: ap_mac.to_string(),
+                    polls_on_ap: 1,
+                    polls: previous.polls + 1,
+                    best_signal_by_ap: previous.best_signal_by_ap,
+                }
+            }
+            Some(mut previous) => {
+                previous.polls_on_ap += 1;
+                previous.polls += 1;
+                previous
+            }
+            None => ClientState {
+                ap_mac: ap_mac.to_string(),
+                polls_on_ap: 1,
+                polls: 1,
+                best_signal_by_ap: HashMap::new(),
+            },
+        };
+
+        // Only recent signals show that a better AP is in reach
+        let polls = state.polls;
+        state
+            .best_signal_by_ap
+            .retain(|_, seen| polls - seen.seen_at < STICKY_HISTORY_POLLS);
+
+        if let Some(signal) = client.signal {
+            let seen = state
+                .best_signal_by_ap
+                .entry(ap_mac.to_string())
+                .or_insert(ApSignal {
+                    best: signal,
+                    seen_at: polls,
+                });
+            seen.best = seen.best.max(signal);
+            seen.seen_at = polls;
+
+            let better_ap_available = state
+                .best_signal_by_ap
+                .iter()
+                .any(|(other, seen)| other != ap_mac && seen.best >= signal + STICKY_MARGIN_DB);
+            if signal < STICKY_SIGNAL_DBM
+                && state.polls_on_ap >= STICKY_MIN_POLLS
+                && better_ap_available
+            {
+                self.client_sticky
+                    .with_label_values(&[
+                        client._id.as_str(),
+                        client.mac.as_str(),
+                        hostname,
+                        ap_mac,
+                    ])
+                    .set(1);
+            }
+        }
+
+        state
     }
 
     pub fn update_sites(&mut self, sites: &[Site]) {
@@ -467,6 +997,39 @@ impl Metrics {
             .set(sites.len() as i64);
     }
 
+    pub fn set_auth_failure(&self, rejected: bool) {
+        let empty_labels: &[&str] = &[];
+        self.auth_failure
+            .with_label_values(empty_labels)
+            .set(i64::from(rejected));
+    }
+
+    /// Record how long a poll took. Returns whether it overran the interval.
+    pub fn record_poll(&self, duration: Duration, interval: Duration) -> bool {
+        let empty_labels: &[&str] = &[];
+        self.poll_duration_seconds
+            .with_label_values(empty_labels)
+            .set(duration.as_secs_f64());
+        let overran = duration > interval;
+        let overruns = self.poll_overruns_total.with_label_values(empty_labels);
+        if overran {
+            overruns.inc();
+        }
+        overran
+    }
+
+    pub fn record_error(&self, endpoint: &str, error: &UniFiError) {
+        self.exporter_errors_total
+            .with_label_values(&[endpoint, error.kind()])
+            .inc();
+    }
+
+    pub fn record_parse_errors(&self, endpoint: &str, field: &str, count: u64) {
+        self.exporter_parse_errors_total
+            .with_label_values(&[endpoint, field])
+            .inc_by(count);
+    }
+
     pub fn gather(&self) -> String {
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -474,12 +1037,53 @@ impl Metrics {
         encoder.encode(&metric_families, &mut buffer).unwrap();
         String::from_utf8(buffer).unwrap_or_default()
     }
+
+    /// The metrics as JSON: one object per metric family with its name,
+    /// help, type and samples.
+    pub fn gather_json(&self) -> Value {
+        let families = self
+            .registry
+            .gather()
+            .iter()
+            .map(|family| {
+                let (kind, counter) = match family.get_field_type() {
+                    MetricType::COUNTER => ("counter", true),
+                    MetricType::GAUGE => ("gauge", false),
+                    _ => ("untyped", false),
+                };
+                let samples: Vec<Value> = family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| {
+                        let labels: Map<String, Value> = metric
+                            .get_label()
+                            .iter()
+                            .map(|label| (label.name().to_string(), json!(label.value())))
+                            .collect();
+                        let value = if counter {
+                            metric.get_counter().value()
+                        } else {
+                            metric.get_gauge().value()
+                        };
+                        json!({"labels": labels, "value": value})
+                    })
+                    .collect();
+                json!({
+                    "name": family.name(),
+                    "help": family.help(),
+                    "type": kind,
+                    "samples": samples,
+                })
+            })
+            .collect();
+        Value::Array(families)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::unifi::{Client, Device, DeviceStats, Site, SysStats};
+    use crate::unifi::{Client, Device, DeviceStats, LldpEntry, Site, SysStats, Uplink};
 
     #[test]
     fn test_metrics_creation() {
@@ -507,6 +1111,7 @@ mod tests {
             uptime: Some(100),
             sys_stats: None,
             stat: None,
+            ..Default::default()
         }];
 
         metrics.update_devices(&devices);
@@ -547,6 +1152,7 @@ mod tests {
                     tx_packets: Some(1000),
                     rx_packets: Some(2000),
                 }),
+                ..Default::default()
             },
             Device {
                 _id: "device2".to_string(),
@@ -560,6 +1166,7 @@ mod tests {
                 uptime: None,
                 sys_stats: None,
                 stat: None,
+                ..Default::default()
             },
         ];
 
@@ -611,6 +1218,7 @@ mod tests {
                 uptime: Some(3600),
                 is_wired: false,
                 is_guest: false,
+                ..Default::default()
             },
             Client {
                 _id: "client2".to_string(),
@@ -627,6 +1235,7 @@ mod tests {
                 uptime: Some(1800),
                 is_wired: true,
                 is_guest: true,
+                ..Default::default()
             },
         ];
 
@@ -699,6 +1308,7 @@ mod tests {
             uptime: None,
             sys_stats: None,
             stat: None,
+            ..Default::default()
         }];
 
         metrics.update_devices(&devices);
@@ -730,6 +1340,7 @@ mod tests {
                 mem_used: Some(750),
             }),
             stat: None,
+            ..Default::default()
         }];
 
         metrics.update_devices(&devices);
@@ -740,6 +1351,357 @@ mod tests {
         assert!(output.contains("0.75")); // 750/1000 = 0.75
     }
 
+    #[test]
+    fn test_firmware_upgrade_metrics() {
+        let mut metrics = Metrics::new().unwrap();
+        let devices = vec![
+            Device {
+                _id: "sw1".to_string(),
+                name: Some("Core Switch".to_string()),
+                mac: "00:11:22:33:44:01".to_string(),
+                device_type: "usw".to_string(),
+                model: Some("US24".to_string()),
+                version: Some("6.5.59".to_string()),
+                upgradable: true,
+                upgrade_to_firmware: Some("7.0.50".to_string()),
+                upgrade_state: Some(0),
+                ..Default::default()
+            },
+            Device {
+                _id: "sw2".to_string(),
+                name: Some("Edge Switch".to_string()),
+                mac: "00:11:22:33:44:02".to_string(),
+                device_type: "usw".to_string(),
+                model: Some("US24".to_string()),
+                version: Some("7.0.50".to_string()),
+                ..Default::default()
+            },
+            Device {
+                _id: "sw3".to_string(),
+                name: Some("Lab Switch".to_string()),
+                mac: "00:11:22:33:44:03".to_string(),
+                device_type: "usw".to_string(),
+                model: Some("US24".to_string()),
+                version: Some("7.0.50".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        metrics.update_devices(&devices);
+        let output = metrics.gather();
+
+        assert!(output.contains(
+            r#"unifi_device_upgradable{id="sw1",mac="00:11:22:33:44:01",name="Core Switch"} 1"#
+        ));
+        assert!(output.contains(
+            r#"unifi_device_upgradable{id="sw2",mac="00:11:22:33:44:02",name="Edge Switch"} 0"#
+        ));
+        assert!(output.contains(
+            r#"unifi_device_upgrade_state{id="sw1",mac="00:11:22:33:44:01",name="Core Switch"} 0"#
+        ));
+        assert!(output.contains(r#"unifi_device_upgrade_info{id="sw1",mac="00:11:22:33:44:01",name="Core Switch",required_version="",target_version="7.0.50",version="6.5.59"} 1"#));
+        assert!(!output.contains(r#"unifi_device_upgrade_info{id="sw2""#));
+        assert!(
+            output.contains(r#"unifi_device_firmware_versions{model="US24",version="6.5.59"} 1"#)
+        );
+        assert!(
+            output.contains(r#"unifi_device_firmware_versions{model="US24",version="7.0.50"} 2"#)
+        );
+    }
+
+    #[test]
+    fn test_uplink_metrics() {
+        let mut metrics = Metrics::new().unwrap();
+        let devices = vec![
+            Device {
+                _id: "ap1".to_string(),
+                name: Some("Wired AP".to_string()),
+                mac: "00:11:22:33:44:01".to_string(),
+                device_type: "uap".to_string(),
+                uplink_depth: Some(1),
+                uplink: Some(Uplink {
+                    uplink_type: Some("wire".to_string()),
+                    uplink_mac: Some("00:11:22:33:44:ff".to_string()),
+                    uplink_remote_port: Some(5),
+                    speed: Some(1000),
+                    full_duplex: Some(true),
+                    signal: None,
+                }),
+                ..Default::default()
+            },
+            Device {
+                _id: "ap2".to_string(),
+                name: Some("Mesh AP".to_string()),
+                mac: "00:11:22:33:44:02".to_string(),
+                device_type: "uap".to_string(),
+                uplink_depth: Some(2),
+                uplink: Some(Uplink {
+                    uplink_type: Some("wireless".to_string()),
+                    uplink_mac: Some("00:11:22:33:44:01".to_string()),
+                    signal: Some(-61),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Device {
+                _id: "ap3".to_string(),
+                name: Some("Offline AP".to_string()),
+                mac: "00:11:22:33:44:03".to_string(),
+                device_type: "uap".to_string(),
+                last_uplink: Some(Uplink {
+                    uplink_type: Some("wire".to_string()),
+                    uplink_mac: Some("00:11:22:33:44:ff".to_string()),
+                    uplink_remote_port: Some(6),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+
+        metrics.update_devices(&devices);
+        let output = metrics.gather();
+
+        assert!(output.contains(r#"unifi_device_uplink_info{id="ap1",mac="00:11:22:33:44:01",name="Wired AP",parent_mac="00:11:22:33:44:ff",parent_port="5",type="wire"} 1"#));
+        assert!(output.contains(r#"unifi_device_uplink_info{id="ap2",mac="00:11:22:33:44:02",name="Mesh AP",parent_mac="00:11:22:33:44:01",parent_port="",type="mesh"} 1"#));
+        assert!(output.contains(r#"unifi_device_uplink_info{id="ap3",mac="00:11:22:33:44:03",name="Offline AP",parent_mac="00:11:22:33:44:ff",parent_port="6",type="wire"} 1"#));
+        assert!(output.contains(
+            r#"unifi_device_uplink_speed_mbps{id="ap1",mac="00:11:22:33:44:01",name="Wired AP"} 1000"#
+        ));
+        assert!(output.contains(
+            r#"unifi_device_uplink_full_duplex{id="ap1",mac="00:11:22:33:44:01",name="Wired AP"} 1"#
+        ));
+        assert!(output.contains(
+            r#"unifi_device_uplink_signal_dbm{id="ap2",mac="00:11:22:33:44:02",name="Mesh AP"} -61"#
+        ));
+        assert!(!output.contains(r#"unifi_device_uplink_signal_dbm{id="ap1""#));
+        assert!(output.contains(
+            r#"unifi_device_uplink_depth{id="ap2",mac="00:11:22:33:44:02",name="Mesh AP"} 2"#
+        ));
+    }
+
+    #[test]
+    fn test_lldp_neighbor_metrics() {
+        let mut metrics = Metrics::new().unwrap();
+        let devices = vec![Device {
+            _id: "sw1".to_string(),
+            name: Some("Core Switch".to_string()),
+            mac: "00:11:22:33:44:01".to_string(),
+            device_type: "usw".to_string(),
+            lldp_table: vec![
+                LldpEntry {
+                    local_port_idx: Some(24),
+                    local_port_name: Some("Port 24".to_string()),
+                    chassis_id: Some("aa:bb:cc:00:11:22".to_string()),
+                    port_id: Some("ge-0/0/1".to_string()),
+                    system_name: Some("fw01".to_string()),
+                },
+                LldpEntry {
+                    local_port_idx: Some(3),
+                    chassis_id: Some("aa:bb:cc:00:11:33".to_string()),
+                    port_id: Some("eth0".to_string()),
+                    ..Default::default()
+                },
+                LldpEntry {
+                    local_port_idx: Some(1),
+                    local_port_name: Some("Port 1".to_string()),
+                    chassis_id: Some("00:00:0c:12:34:56".to_string()),
+                    port_id: Some("Gi1/0/48".to_string()),
+                    system_name: Some("core-sw".to_string()),
+                },
+            ],
+            ..Default::default()
+        }];
+
+        metrics.update_devices(&devices);
+        let output = metrics.gather();
+
+        assert!(output.contains(r#"unifi_lldp_neighbor_info{chassis_id="aa:bb:cc:00:11:22",id="sw1",local_port="Port 24",mac="00:11:22:33:44:01",name="Core Switch",port_id="ge-0/0/1",system_name="fw01",vendor="randomized"} 1"#));
+        assert!(output.contains(r#"unifi_lldp_neighbor_info{chassis_id="aa:bb:cc:00:11:33",id="sw1",local_port="3",mac="00:11:22:33:44:01",name="Core Switch",port_id="eth0",system_name="",vendor="randomized"} 1"#));
+        // Globally administered chassis IDs are looked up in the OUI database
+        assert!(output.contains(r#"unifi_lldp_neighbor_info{chassis_id="00:00:0c:12:34:56",id="sw1",local_port="Port 1",mac="00:11:22:33:44:01",name="Core Switch",port_id="Gi1/0/48",system_name="core-sw",vendor="Cisco Systems, Inc"} 1"#));
+    }
+
+    fn wireless_client(ap_mac: &str, signal: i32) -> Client {
+        Client {
+            _id: "client1".to_string(),
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            hostname: Some("phone".to_string()),
+            ap_mac: Some(ap_mac.to_string()),
+            signal: Some(signal),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_client_roaming() {
+        let mut metrics = Metrics::new().unwrap();
+
+        metrics.update_clients(&[wireless_client("ap:01", -60)]);
+        metrics.update_clients(&[wireless_client("ap:01", -60)]);
+        let output = metrics.gather();
+        assert!(!output.contains("unifi_client_roams_total"));
+
+        metrics.update_clients(&[wireless_client("ap:02", -55)]);
+        metrics.update_clients(&[wireless_client("ap:01", -60)]);
+        let output = metrics.gather();
+        assert!(output.contains(r#"unifi_client_roams_total{from_ap="ap:01",to_ap="ap:02"} 1"#));
+        assert!(output.contains(r#"unifi_client_roams_total{from_ap="ap:02",to_ap="ap:01"} 1"#));
+        assert!(output.contains(r#"unifi_ap_roams_total{ap_mac="ap:01",direction="in"} 1"#));
+        assert!(output.contains(r#"unifi_ap_roams_total{ap_mac="ap:01",direction="out"} 1"#));
+        // Per-client counters are opt-in
+        assert!(!output.contains("unifi_client_roam_events_total{"));
+
+        // A client that disappears and comes back on another AP is not a roam
+        metrics.update_clients(&[]);
+        metrics.update_clients(&[wireless_client("ap:02", -60)]);
+        let output = metrics.gather();
+        assert!(output.contains(r#"unifi_client_roams_total{from_ap="ap:01",to_ap="ap:02"} 1"#));
+    }
+
+    #[test]
+    fn test_client_roam_details() {
+        let mut metrics = Metrics::new().unwrap().with_client_roam_details(true);
+
+        metrics.update_clients(&[wireless_client("ap:01", -60)]);
+        metrics.update_clients(&[wireless_client("ap:02", -60)]);
+        metrics.update_clients(&[wireless_client("ap:03", -60)]);
+        let output = metrics.gather();
+        assert!(output.contains(
+            r#"unifi_client_roam_events_total{hostname="phone",id="client1",mac="aa:bb:cc:dd:ee:ff"} 2"#
+        ));
+    }
+
+    #[test]
+    fn test_sticky_client() {
+        let mut metrics = Metrics::new().unwrap();
+
+        // Good signal on ap:01, then the client moves to ap:02 and stays there
+        // with a poor signal
+        metrics.update_clients(&[wireless_client("ap:01", -55)]);
+        metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        assert!(!metrics.gather().contains("unifi_client_sticky{"));
+
+        metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        assert!(metrics.gather().contains(
+            r#"unifi_client_sticky{ap_mac="ap:02",hostname="phone",id="client1",mac="aa:bb:cc:dd:ee:ff"} 1"#
+        ));
+
+        // Poor signal without a better AP in its history is not sticky
+        let mut metrics = Metrics::new().unwrap();
+        for _ in 0..5 {
+            metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        }
+        assert!(!metrics.gather().contains("unifi_client_sticky{"));
+    }
+
+    #[test]
+    fn test_sticky_client_history_expires() {
+        let mut metrics = Metrics::new().unwrap();
+
+        // A good signal on ap:01 long ago says nothing about reaching it now
+        metrics.update_clients(&[wireless_client("ap:01", -55)]);
+        for _ in 1..STICKY_HISTORY_POLLS {
+            metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        }
+        assert!(metrics.gather().contains("unifi_client_sticky{"));
+
+        metrics.update_clients(&[wireless_client("ap:02", -80)]);
+        assert!(!metrics.gather().contains("unifi_client_sticky{"));
+    }
+
+    #[test]
+    fn test_client_vendor() {
+        let mut metrics = Metrics::new().unwrap();
+        let clients = vec![
+            Client {
+                _id: "c1".to_string(),
+                mac: "b8:27:eb:00:00:01".to_string(),
+                ..Default::default()
+            },
+            Client {
+                _id: "c2".to_string(),
+                mac: "b8:27:eb:00:00:02".to_string(),
+                ..Default::default()
+            },
+            Client {
+                _id: "c3".to_string(),
+                mac: "da:a1:19:00:00:03".to_string(),
+                ..Default::default()
+            },
+            Client {
+                _id: "c4".to_string(),
+                mac: "00:00:01:00:00:04".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        metrics.update_clients(&clients);
+        let output = metrics.gather();
+
+        assert!(output.contains(r#"id="c1",ip="",mac="b8:27:eb:00:00:01",name="",network="unknown",vendor="Raspberry Pi Foundation"} 1"#));
+        assert!(output.contains(
+            r#"mac="da:a1:19:00:00:03",name="",network="unknown",vendor="randomized"} 1"#
+        ));
+        assert!(
+            output.contains(
+                r#"mac="00:00:01:00:00:04",name="",network="unknown",vendor="unknown"} 1"#
+            )
+        );
+        assert!(
+            output.contains(r#"unifi_clients_by_vendor_total{vendor="Raspberry Pi Foundation"} 2"#)
+        );
+        assert!(output.contains(r#"unifi_clients_by_vendor_total{vendor="randomized"} 1"#));
+        assert!(output.contains(r#"unifi_clients_by_vendor_total{vendor="unknown"} 1"#));
+    }
+
+    #[test]
+    fn test_record_error() {
+        let metrics = Metrics::new().unwrap();
+        let error = UniFiError::NotFound {
+            url: "https://controller/api/s/typo/stat/device".to_string(),
+        };
+        metrics.record_error("stat/device", &error);
+        metrics.record_error("stat/device", &error);
+        metrics.record_error("login", &UniFiError::AuthenticationFailed);
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(
+                r#"unifi_exporter_errors_total{endpoint="stat/device",kind="not_found"} 2"#
+            )
+        );
+        assert!(
+            output.contains(
+                r#"unifi_exporter_errors_total{endpoint="login",kind="authentication"} 1"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_record_poll() {
+        let metrics = Metrics::new().unwrap();
+        let interval = Duration::from_secs(30);
+        assert!(!metrics.record_poll(Duration::from_millis(1500), interval));
+        let output = metrics.gather();
+        assert!(output.contains("unifi_exporter_poll_duration_seconds 1.5"));
+        assert!(output.contains("unifi_exporter_poll_overruns_total 0"));
+
+        assert!(metrics.record_poll(Duration::from_secs(45), interval));
+        let output = metrics.gather();
+        assert!(output.contains("unifi_exporter_poll_duration_seconds 45"));
+        assert!(output.contains("unifi_exporter_poll_overruns_total 1"));
+    }
+
+    #[test]
+    fn test_auth_failure() {
+        let metrics = Metrics::new().unwrap();
+        metrics.set_auth_failure(true);
+        assert!(metrics.gather().contains("unifi_auth_failure 1"));
+        metrics.set_auth_failure(false);
+        assert!(metrics.gather().contains("unifi_auth_failure 0"));
+    }
+
     #[test]
     fn test_client_counts() {
         let mut metrics = Metrics::new().unwrap();
@@ -760,6 +1722,7 @@ mod tests {
                 uptime: None,
                 is_wired: true,
                 is_guest: false,
+                ..Default::default()
             },
             // Wireless guest client
             Client {
@@ -777,6 +1740,7 @@ mod tests {
                 uptime: None,
                 is_wired: false,
                 is_guest: true,
+                ..Default::default()
             },
             // Another wireless non-guest client
             Client {
@@ -794,6 +1758,7 @@ mod tests {
                 uptime: None,
                 is_wired: false,
                 is_guest: false,
+                ..Default::default()
             },
         ];
 
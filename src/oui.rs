@@ -0,0 +1,228 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// OUI registrations bundled with the exporter: one `<assignment>\t<name>`
+/// line per block, generated from the IEEE MA-L, MA-M and MA-S registries by
+/// `scripts/update-oui.py`, whose header lists the sources it was built from.
+const EMBEDDED_OUI: &str = include_str!("../data/oui.txt");
+
+/// Vendor label for locally administered (randomized / private) addresses.
+pub const RANDOMIZED: &str = "randomized";
+
+/// Vendor label for addresses without a matching registration.
+pub const UNKNOWN: &str = "unknown";
+
+/// IEEE MAC address block assignments mapped to organization names.
+#[derive(Debug, Clone, Default)]
+pub struct OuiDatabase {
+    /// Keyed by prefix length in bits (24 for MA-L, 28 for MA-M, 36 for MA-S)
+    prefixes: HashMap<u32, HashMap<u64, String>>,
+}
+
+impl OuiDatabase {
+    /// The database bundled with the exporter.
+    pub fn embedded() -> Self {
+        let mut db = OuiDatabase::default();
+        db.load_compact(EMBEDDED_OUI)
+            .expect("embedded OUI database is valid");
+        db
+    }
+
+    /// The bundled database extended (and overridden) by an IEEE CSV file
+    /// (`oui.csv`, `mam.csv` or `oui36.csv`).
+    pub fn with_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read OUI file {}: {e}", path.display()))?;
+        let mut db = Self::embedded();
+        db.load_csv(&contents)?;
+        Ok(db)
+    }
+
+    fn load_csv(&mut self, contents: &str) -> Result<()> {
+        for (line_no, line) in contents.lines().enumerate() {
+            let fields = split_csv_line(line);
+            if fields.len() < 3 || fields[0] == "Registry" {
+                continue;
+            }
+            self.insert(&fields[1], &fields[2], line_no + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Load the format of `data/oui.txt`, skipping `#` comments.
+    fn load_compact(&mut self, contents: &str) -> Result<()> {
+        for (line_no, line) in contents.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (assignment, organization) = line
+                .split_once('\t')
+                .ok_or_else(|| anyhow!("Missing organization on line {}", line_no + 1))?;
+            self.insert(assignment, organization, line_no + 1)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, assignment: &str, organization: &str, line_no: usize) -> Result<()> {
+        let assignment = assignment.trim();
+        let prefix = u64::from_str_radix(assignment, 16)
+            .map_err(|_| anyhow!("Invalid OUI assignment '{assignment}' on line {line_no}"))?;
+        let bits = assignment.len() as u32 * 4;
+        self.prefixes
+            .entry(bits)
+            .or_default()
+            .insert(prefix, organization.trim().to_string());
+        Ok(())
+    }
+
+    /// Vendor label for a MAC address: the registered organization,
+    /// [`RANDOMIZED`] for locally administered addresses, or [`UNKNOWN`].
+    pub fn vendor(&self, mac: &str) -> &str {
+        let Some(address) = parse_mac(mac) else {
+            return UNKNOWN;
+        };
+
+        // Locally administered bit of the first octet
+        if (address >> 40) & 0x02 != 0 {
+            return RANDOMIZED;
+        }
+
+        // Longest (most specific) assignment wins
+        for bits in [36, 28, 24] {
+            if let Some(vendor) = self
+                .prefixes
+                .get(&bits)
+                .and_then(|prefixes| prefixes.get(&(address >> (48 - bits))))
+            {
+                return vendor;
+            }
+        }
+        UNKNOWN
+    }
+}
+
+/// Parse a MAC address in `aa:bb:cc:dd:ee:ff`, `aa-bb-...` or bare hex form.
+fn parse_mac(mac: &str) -> Option<u64> {
+    let hex: String = mac
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .collect();
+    if hex.len() != 12 {
+        return None;
+    }
+    u64::from_str_radix(&hex, 16).ok()
+}
+
+/// Split one line of the IEEE CSV files, which quote fields containing commas.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_lookup() {
+        let db = OuiDatabase::embedded();
+        assert_eq!(db.vendor("b8:27:eb:12:34:56"), "Raspberry Pi Foundation");
+        assert_eq!(db.vendor("F0:9F:C2:00:00:01"), "Ubiquiti Inc");
+        assert_eq!(db.vendor("00-11-32-aa-bb-cc"), "Synology Incorporated");
+        assert_eq!(db.vendor("000393aabbcc"), "Apple, Inc.");
+    }
+
+    #[test]
+    fn test_randomized_and_unknown() {
+        let db = OuiDatabase::embedded();
+        // Second-least-significant bit of the first octet set
+        assert_eq!(db.vendor("da:a1:19:00:00:01"), RANDOMIZED);
+        assert_eq!(db.vendor("02:42:ac:11:00:02"), RANDOMIZED);
+        assert_eq!(db.vendor("00:00:01:00:00:01"), UNKNOWN);
+        assert_eq!(db.vendor("not-a-mac"), UNKNOWN);
+        assert_eq!(db.vendor(""), UNKNOWN);
+    }
+
+    #[test]
+    fn test_with_file_overrides_and_extends() {
+        let path = std::env::temp_dir().join(format!("oui-test-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "Registry,Assignment,Organization Name,Organization Address\n\
+             MA-L,001132,\"Synology, Renamed\",\"Taipei, TW\"\n\
+             MA-L,0CDEF0,Example Corp,Somewhere\n\
+             MA-M,0CDEF01,Example Sub-Block,Somewhere\n",
+        )
+        .unwrap();
+
+        let db = OuiDatabase::with_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(db.vendor("00:11:32:00:00:01"), "Synology, Renamed");
+        assert_eq!(db.vendor("0c:de:f0:00:00:01"), "Example Corp");
+        assert_eq!(db.vendor("0c:de:f0:10:00:01"), "Example Sub-Block");
+        // Embedded entries stay available
+        assert_eq!(db.vendor("b8:27:eb:00:00:01"), "Raspberry Pi Foundation");
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut db = OuiDatabase::default();
+        db.load_csv("MA-L,001122,Block Owner,\nMA-M,0011223,Sub-Block Owner,\n")
+            .unwrap();
+        assert_eq!(db.vendor("00:11:22:30:00:01"), "Sub-Block Owner");
+        assert_eq!(db.vendor("00:11:22:40:00:01"), "Block Owner");
+    }
+
+    #[test]
+    fn test_with_file_missing() {
+        let err = OuiDatabase::with_file(Path::new("/nonexistent/oui.csv")).unwrap_err();
+        assert!(err.to_string().contains("Failed to read OUI file"));
+    }
+
+    #[test]
+    fn test_load_compact() {
+        let mut db = OuiDatabase::default();
+        db.load_compact(
+            "# Generated from:\n#   oui.csv\n0CDEF0\tExample Corp\n0CDEF01\tExample Sub-Block\n",
+        )
+        .unwrap();
+        assert_eq!(db.vendor("0c:de:f0:00:00:01"), "Example Corp");
+        assert_eq!(db.vendor("0c:de:f0:10:00:01"), "Example Sub-Block");
+
+        let err = db.load_compact("0CDEF0 Example Corp\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn test_invalid_assignment() {
+        let mut db = OuiDatabase::default();
+        let err = db.load_csv("MA-L,XYZ123,Bad,\n").unwrap_err();
+        assert!(err.to_string().contains("Invalid OUI assignment"));
+    }
+
+    #[test]
+    fn test_split_csv_line() {
+        assert_eq!(
+            split_csv_line(r#"MA-L,001132,"Synology ""NAS"", Inc",addr"#),
+            ["MA-L", "001132", r#"Synology "NAS", Inc"#, "addr"]
+        );
+    }
+}
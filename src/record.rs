@@ -0,0 +1,407 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::backend::endpoint_file_name;
+
+/// Keys whose values are replaced outright.
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "passphrase",
+    "secret",
+    "token",
+    "psk",
+    "key",
+    "serial",
+];
+
+/// Keys holding names people gave to devices, clients, networks or sites.
+const NAME_KEYS: &[&str] = &[
+    "name",
+    "hostname",
+    "desc",
+    "alias",
+    "note",
+    "essid",
+    "ssid",
+    "email",
+    "username",
+    "system_name",
+    "device_name",
+    "uplink_device_name",
+    "last_uplink_name",
+];
+
+/// Writes every controller response to disk, redacted, so a capture can be
+/// attached to a bug report and replayed with `--replay-dir`.
+///
+/// Responses are stored as `<dir>/<endpoint>/<unix milliseconds>.json`, with
+/// the endpoint named like the replay files (`stat_device`, `stat_sta`,
+/// `self_sites`). Integration API responses are kept for bug reports under
+/// `integration_...`; replay reads the classic API data converted from them.
+pub struct Recorder {
+    dir: PathBuf,
+    redactor: Mutex<Redactor>,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            redactor: Mutex::new(Redactor::default()),
+        }
+    }
+
+    /// Record the response to a request for `path`, relative to the API
+    /// root (`stat/device`, `self/sites`, `integration/sites`).
+    pub fn record(&self, path: &str, body: &str) {
+        let Ok(value) = serde_json::from_str::<Value>(body) else {
+            debug!("Not recording non-JSON response for {}", path);
+            return;
+        };
+        self.record_value(path, value);
+    }
+
+    /// Record a response already parsed, or built from other responses.
+    pub fn record_value(&self, path: &str, mut value: Value) {
+        self.redactor.lock().unwrap().redact(&mut value);
+
+        let dir = self
+            .dir
+            .join(endpoint_file_name(path).trim_end_matches(".json"));
+        if let Err(e) = write_snapshot(&dir, &value) {
+            warn!(
+                "Failed to record {} response in {}: {}",
+                path,
+                dir.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write_snapshot(dir: &Path, value: &Value) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    // Several responses of one endpoint may arrive within a millisecond;
+    // `_` sorts after `.`, so replay keeps them in order
+    let mut path = dir.join(format!("{millis}.json"));
+    let mut suffix = 1;
+    while path.exists() {
+        path = dir.join(format!("{millis}_{suffix:03}.json"));
+        suffix += 1;
+    }
+    let body = serde_json::to_vec_pretty(value).map_err(std::io::Error::other)?;
+    std::fs::write(path, body)
+}
+
+/// Path of a request URL relative to the API root, as recordings are named.
+pub(crate) fn record_path(url: &str, base_url: &str, site: &str) -> String {
+    let path = url.strip_prefix(base_url).unwrap_or(url);
+    let path = path.split('?').next().unwrap_or_default();
+    let path = path.strip_prefix("/proxy/network").unwrap_or(path);
+    let site_prefix = format!("/api/s/{site}/");
+    if let Some(rest) = path.strip_prefix(&site_prefix) {
+        rest.to_string()
+    } else if let Some(rest) = path.strip_prefix("/api/") {
+        rest.to_string()
+    } else if let Some(rest) = path.strip_prefix("/integration/v1/") {
+        format!("integration/{rest}")
+    } else {
+        path.trim_start_matches('/').to_string()
+    }
+}
+
+/// Replaces identifying values with stable pseudonyms, so that the same MAC
+/// address, IP address or name maps to the same replacement across all
+/// responses of a capture and relationships (which client is on which AP)
+/// survive.
+#[derive(Debug, Default)]
+struct Redactor {
+    macs: HashMap<String, String>,
+    ips: HashMap<String, String>,
+    names: HashMap<String, String>,
+}
+
+impl Redactor {
+    fn redact(&mut self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    let key = key.to_lowercase();
+                    if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                        if !value.is_null() {
+                            *value = Value::String("REDACTED".to_string());
+                        }
+                    } else if NAME_KEYS.contains(&key.as_str())
+                        && let Value::String(name) = value
+                    {
+                        *name = self.name(name);
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact(item)),
+            Value::String(text) => {
+                if is_mac(text) {
+                    *text = self.mac(text);
+                } else if let Ok(ip) = text.parse::<IpAddr>() {
+                    *text = self.ip(ip);
+                } else if let Some((ip, prefix)) = text.split_once('/')
+                    && prefix.parse::<u8>().is_ok()
+                    && let Ok(ip) = ip.parse::<IpAddr>()
+                {
+                    // Networks in CIDR notation (`ip_subnet`), often the
+                    // gateway's own address
+                    *text = format!("{}/{prefix}", self.ip(ip));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Keeps the vendor part (OUI) of the address, which vendor metrics and
+    /// lookups depend on.
+    fn mac(&mut self, mac: &str) -> String {
+        let mac = mac.to_lowercase().replace('-', ":");
+        let next = self.macs.len() + 1;
+        self.macs
+            .entry(mac.clone())
+            .or_insert_with(|| {
+                format!(
+                    "{}:{:02x}:{:02x}:{:02x}",
+                    &mac[..8],
+                    (next >> 16) & 0xff,
+                    (next >> 8) & 0xff,
+                    next & 0xff
+                )
+            })
+            .clone()
+    }
+
+    /// Maps IPv4 addresses into the RFC 2544 benchmarking range
+    /// (198.18.0.0/15), which holds more distinct addresses than the
+    /// documentation ranges, and IPv6 addresses into the documentation range
+    /// (2001:db8::/32).
+    fn ip(&mut self, ip: IpAddr) -> String {
+        let next = self.ips.len() + 1;
+        self.ips
+            .entry(ip.to_string())
+            .or_insert_with(|| match ip {
+                IpAddr::V4(_) => format!("198.18.{}.{}", (next >> 8) & 0xff, next & 0xff),
+                IpAddr::V6(_) => format!("2001:db8::{next:x}"),
+            })
+            .clone()
+    }
+
+    fn name(&mut self, name: &str) -> String {
+        if name.is_empty() {
+            return String::new();
+        }
+        let next = self.names.len() + 1;
+        self.names
+            .entry(name.to_string())
+            .or_insert_with(|| format!("name-{next}"))
+            .clone()
+    }
+}
+
+/// Whether a string is a MAC address (`aa:bb:cc:dd:ee:ff` or with dashes).
+fn is_mac(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes.len() == 17
+        && bytes.iter().enumerate().all(|(i, b)| {
+            if i % 3 == 2 {
+                *b == b':' || *b == b'-'
+            } else {
+                b.is_ascii_hexdigit()
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ControllerBackend, ReplayBackend};
+    use crate::unifi::UniFiClient;
+    use serde_json::json;
+    use std::time::Duration;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn record_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "unifi-exporter-record-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_redact_identifiers() {
+        let mut value = json!({
+            "data": [
+                {
+                    "_id": "5f1a",
+                    "mac": "F4:E2:C6:12:34:56",
+                    "ip": "192.168.1.10",
+                    "name": "Alice's iPhone",
+                    "hostname": "alices-iphone",
+                    "x_passphrase": "hunter2",
+                    "tx_bytes": 1024,
+                    "type": "uap"
+                },
+                {
+                    "ap_mac": "f4:e2:c6:12:34:56",
+                    "ip": "fe80::1",
+                    "name": "Alice's iPhone",
+                    "last_uplink_name": "",
+                    "x_authkey": null
+                }
+            ]
+        });
+        Redactor::default().redact(&mut value);
+
+        let first = &value["data"][0];
+        let second = &value["data"][1];
+        // Vendor part is kept, the same address maps to the same pseudonym
+        assert_eq!(first["mac"], "f4:e2:c6:00:00:01");
+        assert_eq!(second["ap_mac"], "f4:e2:c6:00:00:01");
+        assert_eq!(first["ip"], "198.18.0.1");
+        assert_eq!(second["ip"], "2001:db8::2");
+        // Keys are visited in sorted order
+        assert_eq!(first["hostname"], "name-1");
+        assert_eq!(first["name"], "name-2");
+        assert_eq!(second["name"], "name-2");
+        assert_eq!(first["x_passphrase"], "REDACTED");
+        // Non-identifying values are untouched
+        assert_eq!(first["_id"], "5f1a");
+        assert_eq!(first["tx_bytes"], 1024);
+        assert_eq!(first["type"], "uap");
+        assert_eq!(second["last_uplink_name"], "");
+        assert!(second["x_authkey"].is_null());
+    }
+
+    #[test]
+    fn test_redact_gateway_networks() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/record/gateway_stat_device.json");
+        let original = std::fs::read_to_string(path).unwrap();
+        let mut value: Value = serde_json::from_str(&original).unwrap();
+        Redactor::default().redact(&mut value);
+
+        let gateway = &value["data"][0];
+        let networks = &gateway["network_table"];
+        // The gateway address in the subnet maps like the address alone
+        assert_eq!(gateway["connect_request_ip"], "198.18.0.1");
+        assert_eq!(networks[0]["ip_subnet"], "198.18.0.1/24");
+        assert_eq!(networks[0]["ipv6_subnet"], "2001:db8::5/64");
+        assert_eq!(networks[1]["vlan"], 30);
+
+        let redacted = value.to_string();
+        for leak in [
+            "192.168.",
+            "10.20.3",
+            "fd12:",
+            "203.0.113.",
+            "74:ac:b9:4e",
+            "Dream Machine",
+            "IoT",
+        ] {
+            assert!(!redacted.contains(leak), "{leak} leaked: {redacted}");
+        }
+    }
+
+    #[test]
+    fn test_is_mac() {
+        assert!(is_mac("f4:e2:c6:12:34:56"));
+        assert!(is_mac("F4-E2-C6-12-34-56"));
+        assert!(!is_mac("f4:e2:c6:12:34"));
+        assert!(!is_mac("not-a-mac-address"));
+    }
+
+    #[test]
+    fn test_record_path() {
+        let base = "https://unifi:8443";
+        assert_eq!(
+            record_path(
+                "https://unifi:8443/proxy/network/api/s/default/stat/device",
+                base,
+                "default"
+            ),
+            "stat/device"
+        );
+        assert_eq!(
+            record_path("https://unifi:8443/api/self/sites", base, "default"),
+            "self/sites"
+        );
+        assert_eq!(
+            record_path(
+                "https://unifi:8443/proxy/network/integration/v1/sites?offset=0&limit=200",
+                base,
+                "default"
+            ),
+            "integration/sites"
+        );
+    }
+
+    #[test]
+    fn test_record_writes_snapshots() {
+        let dir = record_dir("snapshots");
+        let recorder = Recorder::new(&dir);
+        recorder.record("stat/sta", r#"{"data":[{"mac":"aa:bb:cc:dd:ee:ff"}]}"#);
+        recorder.record("stat/sta", r#"{"data":[]}"#);
+        recorder.record("stat/sta", "<html>not json</html>");
+
+        let files: Vec<_> = std::fs::read_dir(dir.join("stat_sta")).unwrap().collect();
+        assert_eq!(files.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recorded_responses_replay() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"meta":{"rc":"ok"},"data":[{"_id":"d1","mac":"f4:e2:c6:12:34:56","type":"uap","name":"Office AP"}]}"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let dir = record_dir("replay");
+        let client = UniFiClient::new(
+            server.uri(),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(5),
+            true,
+        )
+        .unwrap()
+        .with_recorder(Recorder::new(&dir));
+        assert_eq!(
+            client.get_devices().await.unwrap()[0].name.as_deref(),
+            Some("Office AP")
+        );
+
+        let devices = ReplayBackend::new(&dir).get_devices().await.unwrap();
+        assert_eq!(devices[0]._id, "d1");
+        assert_eq!(devices[0].mac, "f4:e2:c6:00:00:01");
+        assert_eq!(devices[0].name.as_deref(), Some("name-1"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
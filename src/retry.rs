@@ -0,0 +1,237 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::unifi::UniFiError;
+
+/// Delay before the first retry; doubled for every further retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest delay between retries. A `Retry-After` beyond this is not waited
+/// for within a poll.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// How often to retry idempotent requests that failed transiently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: RETRY_BASE_DELAY,
+            max_delay: RETRY_MAX_DELAY,
+        }
+    }
+
+    /// Single attempt only.
+    pub fn none() -> Self {
+        Self::new(0)
+    }
+
+    /// Delay before retry number `attempt` (starting at 1), or `None` if the
+    /// controller asked to wait longer than `max_delay`.
+    ///
+    /// Delays grow exponentially with equal jitter (half fixed, half random),
+    /// so exporters restarted together don't retry in lockstep.
+    pub fn delay(&self, attempt: u32, error: &UniFiError) -> Option<Duration> {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let ceiling = (self.base_delay * 2u32.pow(exponent)).min(self.max_delay);
+        let delay = ceiling / 2 + ceiling.mul_f64(random_fraction() / 2.0);
+
+        match error.retry_after() {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(delay.max(retry_after)),
+            None => Some(delay),
+        }
+    }
+}
+
+/// A pseudo-random number in `[0, 1)`, from the randomly seeded std hasher.
+fn random_fraction() -> f64 {
+    (RandomState::new().hash_one(Instant::now()) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops sending requests to a controller that failed `threshold` times in a
+/// row.
+///
+/// While open, requests fail immediately. After `cooldown` one request is let
+/// through as a probe: if it succeeds the breaker closes, otherwise it stays
+/// open for another `cooldown`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the breaker; 0 disables it
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Whether a request may be sent, or the time until the next probe.
+    pub fn check(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => Ok(()),
+            Some(open_until) if now < open_until => Err(open_until - now),
+            Some(_) => {
+                // Let this request probe the controller and hold back the
+                // others until it has an answer
+                state.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+        }
+    }
+
+    /// Record a request the controller answered. Returns whether this closed
+    /// an open breaker.
+    pub fn record_success(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let was_open = state.open_until.is_some();
+        *state = BreakerState::default();
+        was_open
+    }
+
+    /// Record a request that failed because the controller was unavailable.
+    /// Returns whether this opened the breaker.
+    pub fn record_failure(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if self.threshold == 0 || state.consecutive_failures < self.threshold {
+            return false;
+        }
+        let was_closed = state.open_until.is_none();
+        state.open_until = Some(now + self.cooldown);
+        was_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeout() -> UniFiError {
+        UniFiError::Timeout {
+            url: "https://unifi/api/s/default/stat/device".to_string(),
+        }
+    }
+
+    fn rate_limited(seconds: u64) -> UniFiError {
+        UniFiError::RateLimited {
+            url: "https://unifi/api/s/default/stat/device".to_string(),
+            retry_after: Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    #[test]
+    fn test_retry_delay_grows_with_jitter() {
+        let policy = RetryPolicy::new(5);
+        for (attempt, ceiling) in [(1, 500), (2, 1000), (3, 2000), (6, 10_000), (30, 10_000)] {
+            let ceiling = Duration::from_millis(ceiling);
+            for _ in 0..20 {
+                let delay = policy.delay(attempt, &timeout()).unwrap();
+                assert!(delay >= ceiling / 2, "{delay:?} < {:?}", ceiling / 2);
+                assert!(delay <= ceiling, "{delay:?} > {ceiling:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_retry_delay_honours_retry_after() {
+        let policy = RetryPolicy::new(2);
+        assert_eq!(
+            policy.delay(1, &rate_limited(5)),
+            Some(Duration::from_secs(5))
+        );
+        // Longer than a poll should wait
+        assert_eq!(policy.delay(1, &rate_limited(120)), None);
+    }
+
+    #[test]
+    fn test_random_fraction_range() {
+        for _ in 0..100 {
+            let value = random_fraction();
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(!breaker.record_failure(now));
+        assert!(!breaker.record_failure(now));
+        assert_eq!(breaker.check(now), Ok(()));
+        assert!(breaker.record_failure(now));
+        assert_eq!(
+            breaker.check(now + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_success_resets_count() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        breaker.record_failure(now);
+        assert!(!breaker.record_success());
+        assert!(!breaker.record_failure(now));
+        assert_eq!(breaker.check(now), Ok(()));
+    }
+
+    #[test]
+    fn test_circuit_breaker_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        breaker.record_failure(now);
+
+        // After the cooldown one probe goes through, the rest wait for it
+        let later = now + Duration::from_secs(60);
+        assert_eq!(breaker.check(later), Ok(()));
+        assert_eq!(breaker.check(later), Err(Duration::from_secs(60)));
+
+        // A failed probe keeps the breaker open without re-announcing it
+        assert!(!breaker.record_failure(later));
+        assert!(breaker.check(later + Duration::from_secs(30)).is_err());
+
+        // A successful probe closes it
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(breaker.check(much_later), Ok(()));
+        assert!(breaker.record_success());
+        assert_eq!(breaker.check(much_later), Ok(()));
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let breaker = CircuitBreaker::disabled();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(!breaker.record_failure(now));
+        }
+        assert_eq!(breaker.check(now), Ok(()));
+    }
+}
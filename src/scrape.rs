@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::backend::ControllerBackend;
+use crate::config::{ScrapeArgs, ScrapeFormat};
+use crate::exporter::{SharedMetrics, poll_unifi_data};
+use crate::topology::Topology;
+
+/// Poll the controller once and write the metrics to stdout or a file.
+///
+/// Fails, without touching the output file, if any part of the poll fails,
+/// so cron jobs notice and a textfile collector keeps the previous metrics.
+pub async fn run(
+    backend: &dyn ControllerBackend,
+    metrics: &SharedMetrics,
+    args: &ScrapeArgs,
+) -> Result<()> {
+    let topology = Arc::new(RwLock::new(Topology::default()));
+    poll_unifi_data(backend, metrics, &topology).await?;
+
+    let metrics = metrics.read().await;
+    let output = match args.format {
+        ScrapeFormat::Text => metrics.gather(),
+        ScrapeFormat::Json => serde_json::to_string_pretty(&metrics.gather_json())? + "\n",
+    };
+    match &args.output {
+        Some(path) => {
+            write_atomic(path, &output)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            info!("Wrote metrics to {}", path.display());
+        }
+        None => print!("{output}"),
+    }
+
+    Ok(())
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so
+/// readers such as node_exporter never see a partial file.
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("output path has no file name"))?;
+    // Not ending in `.prom`, so the textfile collector skips it
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ReplayBackend;
+    use crate::metrics::Metrics;
+    use std::path::PathBuf;
+
+    fn scrape_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "unifi-exporter-scrape-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_responses(dir: &Path) {
+        std::fs::write(
+            dir.join("stat_device.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"d1","mac":"f4:e2:c6:00:00:01","type":"uap","name":"Office AP","state":1,"adopted":true}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("stat_sta.json"),
+            r#"{"meta":{"rc":"ok"},"data":[]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("self_sites.json"),
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"s1","name":"default","desc":"Default"}]}"#,
+        )
+        .unwrap();
+    }
+
+    fn metrics() -> SharedMetrics {
+        Arc::new(RwLock::new(Metrics::new().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_scrape_to_textfile() {
+        let dir = scrape_dir("text");
+        write_responses(&dir);
+        let output = dir.join("out").join("unifi.prom");
+        std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+        std::fs::write(&output, "stale\n").unwrap();
+
+        let args = ScrapeArgs {
+            format: ScrapeFormat::Text,
+            output: Some(output.clone()),
+        };
+        run(&ReplayBackend::new(&dir), &metrics(), &args)
+            .await
+            .unwrap();
+
+        let text = std::fs::read_to_string(&output).unwrap();
+        assert!(
+            text.contains(
+                r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#
+            )
+        );
+        assert!(!text.contains("stale"));
+        // Only the output file is left behind
+        let files: Vec<_> = std::fs::read_dir(output.parent().unwrap())
+            .unwrap()
+            .collect();
+        assert_eq!(files.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scrape_json() {
+        let dir = scrape_dir("json");
+        write_responses(&dir);
+        let output = dir.join("metrics.json");
+
+        let args = ScrapeArgs {
+            format: ScrapeFormat::Json,
+            output: Some(output.clone()),
+        };
+        run(&ReplayBackend::new(&dir), &metrics(), &args)
+            .await
+            .unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        let state = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|family| family["name"] == "unifi_device_state")
+            .unwrap();
+        assert_eq!(state["type"], "gauge");
+        assert_eq!(state["samples"][0]["labels"]["name"], "Office AP");
+        assert_eq!(state["samples"][0]["value"], 1.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scrape_failure_keeps_output() {
+        // No responses to replay
+        let dir = scrape_dir("failure");
+        let output = dir.join("unifi.prom");
+        std::fs::write(&output, "previous\n").unwrap();
+
+        let args = ScrapeArgs {
+            format: ScrapeFormat::Text,
+            output: Some(output.clone()),
+        };
+        assert!(
+            run(&ReplayBackend::new(&dir), &metrics(), &args)
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "previous\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
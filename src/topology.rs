@@ -0,0 +1,327 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+
+use crate::unifi::{Client, Device};
+
+/// Network graph built from polled devices and clients.
+///
+/// Devices are linked to their parent through `uplink`, wired clients to the
+/// switch port they are patched into (`sw_mac`/`sw_port`) and wireless clients
+/// to the access point they are associated with (`ap_mac`).
+/// Switch ports are not nodes of their own; the port on the upstream node is
+/// the `port` of the link.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Topology {
+    pub nodes: Vec<Node>,
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    pub mac: String,
+    pub name: String,
+    /// `gateway`, `switch`, `ap`, `device` or `client`
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Link {
+    /// MAC of the upstream node
+    pub from: String,
+    /// MAC of the downstream node
+    pub to: String,
+    /// Port on the upstream node, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<i32>,
+    /// `wire`, `wireless` or `mesh`
+    #[serde(rename = "type")]
+    pub link_type: String,
+}
+
+fn device_kind(device_type: &str) -> &'static str {
+    match device_type {
+        "ugw" | "udm" | "uxg" => "gateway",
+        "usw" => "switch",
+        "uap" => "ap",
+        _ => "device",
+    }
+}
+
+impl Topology {
+    pub fn build(devices: &[Device], clients: &[Client]) -> Self {
+        let mut topology = Topology::default();
+        let known: HashSet<&str> = devices.iter().map(|d| d.mac.as_str()).collect();
+
+        for device in devices {
+            topology.nodes.push(Node {
+                mac: device.mac.clone(),
+                name: device.name.clone().unwrap_or_else(|| device.mac.clone()),
+                kind: device_kind(&device.device_type),
+                model: device.model.clone(),
+                ip: None,
+            });
+
+            if let Some(uplink) = device.uplink.as_ref().or(device.last_uplink.as_ref())
+                && let Some(parent) = uplink.uplink_mac.as_deref()
+                && known.contains(parent)
+            {
+                topology.links.push(Link {
+                    from: parent.to_string(),
+                    to: device.mac.clone(),
+                    port: uplink.uplink_remote_port,
+                    link_type: uplink.kind(&device.device_type).to_string(),
+                });
+            }
+        }
+
+        for client in clients {
+            let parent = if client.is_wired {
+                client
+                    .sw_mac
+                    .as_deref()
+                    .map(|mac| (mac, client.sw_port, "wire"))
+            } else {
+                client.ap_mac.as_deref().map(|mac| (mac, None, "wireless"))
+            };
+
+            topology.nodes.push(Node {
+                mac: client.mac.clone(),
+                name: client
+                    .name
+                    .clone()
+                    .or_else(|| client.hostname.clone())
+                    .unwrap_or_else(|| client.mac.clone()),
+                kind: "client",
+                model: None,
+                ip: client.ip.clone(),
+            });
+
+            if let Some((parent, port, link_type)) = parent
+                && known.contains(parent)
+            {
+                topology.links.push(Link {
+                    from: parent.to_string(),
+                    to: client.mac.clone(),
+                    port,
+                    link_type: link_type.to_string(),
+                });
+            }
+        }
+
+        topology
+    }
+
+    /// Render the topology as a Graphviz DOT digraph.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph unifi {\n    rankdir=LR;\n");
+
+        for node in &self.nodes {
+            let shape = match node.kind {
+                "gateway" => "doubleoctagon",
+                "switch" => "box3d",
+                "ap" => "ellipse",
+                "client" => "note",
+                _ => "box",
+            };
+            let mut label = node.name.clone();
+            if let Some(model) = &node.model {
+                label.push('\n');
+                label.push_str(model);
+            }
+            if let Some(ip) = &node.ip {
+                label.push('\n');
+                label.push_str(ip);
+            }
+            let _ = writeln!(
+                out,
+                "    \"{}\" [label=\"{}\", shape={shape}];",
+                escape_dot(&node.mac),
+                escape_dot(&label)
+            );
+        }
+
+        for link in &self.links {
+            let style = if link.link_type == "wire" {
+                "solid"
+            } else {
+                "dashed"
+            };
+            let label = match link.port {
+                Some(port) => format!("port {port}"),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{label}\", style={style}];",
+                escape_dot(&link.from),
+                escape_dot(&link.to)
+            );
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn escape_dot(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unifi::Uplink;
+
+    fn devices() -> Vec<Device> {
+        vec![
+            Device {
+                _id: "gw".to_string(),
+                name: Some("Gateway".to_string()),
+                mac: "00:00:00:00:00:01".to_string(),
+                device_type: "udm".to_string(),
+                model: Some("UDMPRO".to_string()),
+                ..Default::default()
+            },
+            Device {
+                _id: "sw".to_string(),
+                name: Some("Core Switch".to_string()),
+                mac: "00:00:00:00:00:02".to_string(),
+                device_type: "usw".to_string(),
+                uplink: Some(Uplink {
+                    uplink_type: Some("wire".to_string()),
+                    uplink_mac: Some("00:00:00:00:00:01".to_string()),
+                    uplink_remote_port: Some(9),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Device {
+                _id: "ap".to_string(),
+                name: Some("Office AP".to_string()),
+                mac: "00:00:00:00:00:03".to_string(),
+                device_type: "uap".to_string(),
+                uplink: Some(Uplink {
+                    uplink_type: Some("wire".to_string()),
+                    uplink_mac: Some("00:00:00:00:00:02".to_string()),
+                    uplink_remote_port: Some(4),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Device {
+                _id: "mesh".to_string(),
+                name: Some("Garden AP".to_string()),
+                mac: "00:00:00:00:00:04".to_string(),
+                device_type: "uap".to_string(),
+                uplink: Some(Uplink {
+                    uplink_type: Some("wireless".to_string()),
+                    uplink_mac: Some("00:00:00:00:00:03".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn clients() -> Vec<Client> {
+        vec![
+            Client {
+                _id: "c1".to_string(),
+                mac: "aa:00:00:00:00:01".to_string(),
+                hostname: Some("nas".to_string()),
+                ip: Some("192.168.1.10".to_string()),
+                sw_mac: Some("00:00:00:00:00:02".to_string()),
+                sw_port: Some(12),
+                is_wired: true,
+                ..Default::default()
+            },
+            Client {
+                _id: "c2".to_string(),
+                mac: "aa:00:00:00:00:02".to_string(),
+                ap_mac: Some("00:00:00:00:00:04".to_string()),
+                ..Default::default()
+            },
+            Client {
+                _id: "c3".to_string(),
+                mac: "aa:00:00:00:00:03".to_string(),
+                ap_mac: Some("ff:ff:ff:ff:ff:ff".to_string()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_build_topology() {
+        let topology = Topology::build(&devices(), &clients());
+
+        assert_eq!(topology.nodes.len(), 7);
+        let kinds: Vec<&str> = topology.nodes.iter().map(|n| n.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                "gateway", "switch", "ap", "ap", "client", "client", "client"
+            ]
+        );
+        assert_eq!(topology.nodes[4].name, "nas");
+        assert_eq!(topology.nodes[5].name, "aa:00:00:00:00:02");
+
+        let links: Vec<(&str, &str, Option<i32>, &str)> = topology
+            .links
+            .iter()
+            .map(|l| (l.from.as_str(), l.to.as_str(), l.port, l.link_type.as_str()))
+            .collect();
+        assert_eq!(
+            links,
+            [
+                ("00:00:00:00:00:01", "00:00:00:00:00:02", Some(9), "wire"),
+                ("00:00:00:00:00:02", "00:00:00:00:00:03", Some(4), "wire"),
+                ("00:00:00:00:00:03", "00:00:00:00:00:04", None, "mesh"),
+                ("00:00:00:00:00:02", "aa:00:00:00:00:01", Some(12), "wire"),
+                ("00:00:00:00:00:04", "aa:00:00:00:00:02", None, "wireless"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_topology_json() {
+        let topology = Topology::build(&devices()[..2], &[]);
+        let json = serde_json::to_value(&topology).unwrap();
+        assert_eq!(json["nodes"][0]["kind"], "gateway");
+        assert_eq!(json["nodes"][0]["model"], "UDMPRO");
+        assert!(json["nodes"][1].get("model").is_none());
+        assert_eq!(json["links"][0]["type"], "wire");
+        assert_eq!(json["links"][0]["port"], 9);
+    }
+
+    #[test]
+    fn test_topology_dot() {
+        let topology = Topology::build(&devices(), &clients());
+        let dot = topology.to_dot();
+
+        assert!(dot.starts_with("digraph unifi {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(
+            dot.contains(r#""00:00:00:00:00:01" [label="Gateway\nUDMPRO", shape=doubleoctagon];"#)
+        );
+        assert!(dot.contains(
+            r#""00:00:00:00:00:01" -> "00:00:00:00:00:02" [label="port 9", style=solid];"#
+        ));
+        assert!(
+            dot.contains(r#""00:00:00:00:00:03" -> "00:00:00:00:00:04" [label="", style=dashed];"#)
+        );
+        assert!(dot.contains(r#"[label="nas\n192.168.1.10", shape=note];"#));
+    }
+
+    #[test]
+    fn test_escape_dot() {
+        assert_eq!(escape_dot(r#"say "hi""#), r#"say \"hi\""#);
+    }
+}
@@ -1,13 +1,22 @@
-use anyhow::{Result, anyhow};
-use reqwest::header::{ACCEPT, COOKIE, HeaderMap, HeaderValue};
-use serde::{Deserialize, Deserializer, Serialize};
-use std::sync::Arc;
-use std::time::Duration;
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use cookie::Cookie;
+use reqwest::StatusCode;
+use reqwest::header::{ACCEPT, COOKIE, HeaderMap, HeaderValue, RETRY_AFTER, SET_COOKIE};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio::sync::RwLock;
-use tracing::debug;
+use tokio::sync::{Mutex, OnceCell, RwLock, Semaphore};
+use tracing::{debug, error, info, warn};
 
-use crate::unifi_integration::{IntegrationResponse, IntegrationSite};
+use crate::proxy::{self, ProxyOptions};
+use crate::record::{Recorder, record_path};
+use crate::retry::{CircuitBreaker, RetryPolicy};
+use crate::tls::{self, TlsOptions};
 
 // Helper function to deserialize optional string to f64
 fn deserialize_optional_string_to_f64<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
@@ -22,6 +31,22 @@ where
     }
 }
 
+/// Write back what `deserialize_optional_string_to_f64` reads.
+fn serialize_optional_f64_as_string<S>(
+    value: &Option<f64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(value) => serializer.serialize_some(&value.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub type Result<T, E = UniFiError> = std::result::Result<T, E>;
+
 #[derive(Error, Debug)]
 pub enum UniFiError {
     #[error("HTTP request failed: {0}")]
@@ -32,6 +57,305 @@ pub enum UniFiError {
 
     #[error("Failed to parse response: {0}")]
     ParseError(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Not logging in again for {retry_in:?} after failed login attempts")]
+    LoginBackoff { retry_in: Duration },
+
+    #[error("Request to {url} timed out")]
+    Timeout { url: String },
+
+    #[error("Failed to connect to {url}: {message}")]
+    Connect { url: String, message: String },
+
+    #[error("TLS error connecting to {url}: {message}")]
+    Tls { url: String, message: String },
+
+    #[error("Unauthorized (HTTP 401) for {url}")]
+    Unauthorized { url: String },
+
+    #[error("Forbidden (HTTP 403) for {url}: the account or API key lacks permission")]
+    Forbidden { url: String },
+
+    #[error("Not found (HTTP 404) for {url}: check the site name and controller type")]
+    NotFound { url: String },
+
+    #[error("Rate limited (HTTP 429) by {url}")]
+    RateLimited {
+        url: String,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Controller error (HTTP {status}) for {url}")]
+    ServerError {
+        url: String,
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Not contacting the controller for {retry_in:?} after repeated failures")]
+    CircuitOpen { retry_in: Duration },
+
+    #[error("Unexpected HTTP status {status} for {url}")]
+    HttpStatus { url: String, status: u16 },
+
+    #[error("Failed to decode {endpoint} response at `{path}`: {message}")]
+    Decode {
+        endpoint: String,
+        path: String,
+        message: String,
+    },
+
+    #[error("Controller returned rc={rc} for {endpoint}: {msg}")]
+    Controller {
+        endpoint: String,
+        rc: String,
+        msg: String,
+    },
+}
+
+impl UniFiError {
+    /// Short, stable name of the error class, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UniFiError::RequestFailed(_) => "request",
+            UniFiError::AuthenticationFailed => "authentication",
+            UniFiError::ParseError(_) => "parse",
+            UniFiError::InvalidConfig(_) => "config",
+            UniFiError::LoginBackoff { .. } => "login_backoff",
+            UniFiError::Timeout { .. } => "timeout",
+            UniFiError::Connect { .. } => "connect",
+            UniFiError::Tls { .. } => "tls",
+            UniFiError::Unauthorized { .. } => "unauthorized",
+            UniFiError::Forbidden { .. } => "forbidden",
+            UniFiError::NotFound { .. } => "not_found",
+            UniFiError::RateLimited { .. } => "rate_limited",
+            UniFiError::ServerError { .. } => "server_error",
+            UniFiError::CircuitOpen { .. } => "circuit_open",
+            UniFiError::HttpStatus { .. } => "http_status",
+            UniFiError::Decode { .. } => "decode",
+            UniFiError::Controller { .. } => "controller",
+        }
+    }
+
+    /// The HTTP status the controller answered with, if it answered.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            UniFiError::Unauthorized { .. } => Some(401),
+            UniFiError::Forbidden { .. } => Some(403),
+            UniFiError::NotFound { .. } => Some(404),
+            UniFiError::RateLimited { .. } => Some(429),
+            UniFiError::ServerError { status, .. } | UniFiError::HttpStatus { status, .. } => {
+                Some(*status)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the error suggests the controller is temporarily unavailable,
+    /// so the request is worth retrying.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UniFiError::Timeout { .. }
+            | UniFiError::Connect { .. }
+            | UniFiError::RateLimited { .. } => true,
+            // Bad gateway, unavailable and gateway timeout from the UniFi OS
+            // proxy while the Network application (re)starts
+            UniFiError::ServerError { status, .. } => matches!(status, 502..=504),
+            _ => false,
+        }
+    }
+
+    /// How long the controller asked to wait (`Retry-After`), if it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            UniFiError::RateLimited { retry_after, .. }
+            | UniFiError::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Classify a transport-level `reqwest` error.
+    fn from_reqwest(url: &str, error: reqwest::Error) -> Self {
+        let url = url.to_string();
+        if error.is_timeout() {
+            return UniFiError::Timeout { url };
+        }
+        if error.is_connect() {
+            let message = error_chain(&error);
+            let lower = message.to_lowercase();
+            if lower.contains("certificate") || lower.contains("tls") || lower.contains("handshake")
+            {
+                return UniFiError::Tls { url, message };
+            }
+            return UniFiError::Connect { url, message };
+        }
+        UniFiError::RequestFailed(error)
+    }
+
+    /// Classify an unsuccessful HTTP status.
+    fn from_status(url: &str, status: StatusCode, headers: &HeaderMap) -> Self {
+        let url = url.to_string();
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        match status {
+            StatusCode::UNAUTHORIZED => UniFiError::Unauthorized { url },
+            StatusCode::FORBIDDEN => UniFiError::Forbidden { url },
+            StatusCode::NOT_FOUND => UniFiError::NotFound { url },
+            StatusCode::TOO_MANY_REQUESTS => UniFiError::RateLimited { url, retry_after },
+            status if status.is_server_error() => UniFiError::ServerError {
+                url,
+                status: status.as_u16(),
+                retry_after,
+            },
+            status => UniFiError::HttpStatus {
+                url,
+                status: status.as_u16(),
+            },
+        }
+    }
+}
+
+/// Parse a `Retry-After` value, either seconds or an HTTP date; a date in
+/// the past means no wait.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Join an error with all of its sources, since `reqwest` hides the
+/// interesting part (DNS, refused connection, certificate) in the chain.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// Deserialize a response body, reporting the path to the failing field.
+pub(crate) fn decode<T>(endpoint: &str, body: &str) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let deserializer = &mut serde_json::Deserializer::from_str(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        warn!(endpoint, "Failed to parse response: {}", e);
+        debug!(
+            endpoint,
+            "Response text (first 500 chars): {}",
+            body.chars().take(500).collect::<String>()
+        );
+        UniFiError::Decode {
+            endpoint: endpoint.to_string(),
+            path: e.path().to_string(),
+            message: e.into_inner().to_string(),
+        }
+    })
+}
+
+/// Decode a classic API response and return its `data`, or the error its
+/// `meta` envelope reports.
+///
+/// Records are decoded one by one, so a record with an unexpected field
+/// type is skipped and counted in `parse_errors` instead of failing the
+/// whole response.
+pub(crate) fn decode_legacy<T>(
+    endpoint: &str,
+    body: &str,
+    parse_errors: &ParseErrors,
+) -> Result<Vec<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let api_response: ApiResponse<serde_json::Value> = decode(endpoint, body)?;
+    api_response.meta.check(endpoint)?;
+
+    let mut records = Vec::with_capacity(api_response.data.len());
+    for record in &api_response.data {
+        match serde_path_to_error::deserialize(record) {
+            Ok(value) => records.push(value),
+            Err(e) => {
+                let field = field_name(&e.path().to_string(), &e.inner().to_string());
+                parse_errors.record(endpoint, &field, &e.into_inner().to_string(), record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// The field a decoding error is about, without array indices so the name
+/// works as a metric label: `port_table[3].speed` becomes
+/// `port_table.speed`. Missing fields are reported at their parent, so
+/// their name is taken from the message.
+fn field_name(path: &str, message: &str) -> String {
+    let mut segments: Vec<String> = path
+        .split('.')
+        .map(|segment| match segment.find('[') {
+            Some(index) => segment[..index].to_string(),
+            None => segment.to_string(),
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    if let Some(missing) = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next())
+    {
+        segments.push(missing.to_string());
+    }
+    if segments.is_empty() {
+        ".".to_string()
+    } else {
+        segments.join(".")
+    }
+}
+
+/// Records skipped because they failed to decode, counted by endpoint and
+/// field until taken for the metrics.
+#[derive(Debug, Default)]
+pub struct ParseErrors {
+    counts: std::sync::Mutex<BTreeMap<(String, String), u64>>,
+    /// Endpoint and field pairs a sample record was logged for
+    logged: std::sync::Mutex<HashSet<(String, String)>>,
+}
+
+impl ParseErrors {
+    /// Count a skipped record, logging it the first time its endpoint and
+    /// field fail.
+    fn record(&self, endpoint: &str, field: &str, message: &str, record: &serde_json::Value) {
+        let key = (endpoint.to_string(), field.to_string());
+        if self.logged.lock().unwrap().insert(key.clone()) {
+            warn!(
+                endpoint,
+                field,
+                "Skipping a record that failed to parse: {}; record (first 500 chars): {}",
+                message,
+                record.to_string().chars().take(500).collect::<String>()
+            );
+        } else {
+            debug!(
+                endpoint,
+                field, "Skipping a record that failed to parse: {}", message
+            );
+        }
+        *self.counts.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    /// The records skipped since the last call, by endpoint and field.
+    pub fn take(&self) -> BTreeMap<(String, String), u64> {
+        std::mem::take(&mut *self.counts.lock().unwrap())
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -49,12 +373,25 @@ struct LoginResponse {
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 struct Meta {
     rc: String,
+    msg: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Meta {
+    fn check(&self, endpoint: &str) -> Result<()> {
+        if self.rc == "ok" {
+            return Ok(());
+        }
+        Err(UniFiError::Controller {
+            endpoint: endpoint.to_string(),
+            rc: self.rc.clone(),
+            msg: self.msg.clone().unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Device {
     pub _id: String,
     pub name: Option<String>,
@@ -70,21 +407,80 @@ pub struct Device {
     pub uptime: Option<i64>,
     pub sys_stats: Option<SysStats>,
     pub stat: Option<DeviceStats>,
+    #[serde(default)]
+    pub upgradable: bool,
+    pub upgrade_to_firmware: Option<String>,
+    pub required_version: Option<String>,
+    pub upgrade_state: Option<i32>,
+    pub uplink: Option<Uplink>,
+    pub last_uplink: Option<Uplink>,
+    pub uplink_depth: Option<i32>,
+    #[serde(default)]
+    pub lldp_table: Vec<LldpEntry>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// A neighbor a switch port learned through LLDP.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LldpEntry {
+    pub local_port_idx: Option<i32>,
+    pub local_port_name: Option<String>,
+    pub chassis_id: Option<String>,
+    pub port_id: Option<String>,
+    pub system_name: Option<String>,
+}
+
+/// How a device connects to its parent (`uplink` / `last_uplink`).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Uplink {
+    /// `wire` or `wireless`
+    #[serde(rename = "type")]
+    pub uplink_type: Option<String>,
+    pub uplink_mac: Option<String>,
+    pub uplink_remote_port: Option<i32>,
+    pub speed: Option<i64>,
+    pub full_duplex: Option<bool>,
+    pub signal: Option<i32>,
+}
+
+impl Uplink {
+    /// Classify the uplink as `wire`, `wireless` or `mesh`.
+    ///
+    /// The controller reports access points that uplink through another AP
+    /// as `wireless`; those are mesh links.
+    pub fn kind(&self, device_type: &str) -> &str {
+        match self.uplink_type.as_deref() {
+            Some("wireless") if device_type == "uap" => "mesh",
+            Some(uplink_type) => uplink_type,
+            None => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SysStats {
-    #[serde(default, deserialize_with = "deserialize_optional_string_to_f64")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_string_to_f64",
+        serialize_with = "serialize_optional_f64_as_string"
+    )]
     pub loadavg_1: Option<f64>,
-    #[serde(default, deserialize_with = "deserialize_optional_string_to_f64")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_string_to_f64",
+        serialize_with = "serialize_optional_f64_as_string"
+    )]
     pub loadavg_5: Option<f64>,
-    #[serde(default, deserialize_with = "deserialize_optional_string_to_f64")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_string_to_f64",
+        serialize_with = "serialize_optional_f64_as_string"
+    )]
     pub loadavg_15: Option<f64>,
     pub mem_total: Option<i64>,
     pub mem_used: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct DeviceStats {
     pub bytes: Option<i64>,
@@ -94,7 +490,7 @@ pub struct DeviceStats {
     pub rx_packets: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[allow(dead_code)]
 pub struct Client {
     pub _id: String,
@@ -105,6 +501,8 @@ pub struct Client {
     pub network: Option<String>,
     pub vlan: Option<i32>,
     pub ap_mac: Option<String>,
+    pub sw_mac: Option<String>,
+    pub sw_port: Option<i32>,
     pub signal: Option<i32>,
     pub tx_bytes: Option<i64>,
     pub rx_bytes: Option<i64>,
@@ -115,7 +513,7 @@ pub struct Client {
     pub is_guest: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct Site {
     pub _id: String,
@@ -125,25 +523,387 @@ pub struct Site {
     pub attr_no_delete: Option<bool>,
 }
 
+/// Resolution of the controller's historical `stat/report` data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportInterval {
+    Hourly,
+    Daily,
+}
+
+impl ReportInterval {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportInterval::Hourly => "hourly",
+            ReportInterval::Daily => "daily",
+        }
+    }
+}
+
+/// One row of a `stat/report/<interval>.<type>` response.
+///
+/// The controller reports per-interval deltas, not cumulative counters, and
+/// which fields are present depends on the report type (`ap`, `user`, `site`).
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReportEntry {
+    /// Start of the interval in milliseconds since the Unix epoch
+    pub time: i64,
+    pub ap: Option<String>,
+    pub user: Option<String>,
+    pub tx_bytes: Option<f64>,
+    pub rx_bytes: Option<f64>,
+    #[serde(rename = "lan-num_sta")]
+    pub lan_num_sta: Option<f64>,
+    #[serde(rename = "wlan-num_sta")]
+    pub wlan_num_sta: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportRequest<'a> {
+    attrs: &'a [&'a str],
+    start: i64,
+    end: i64,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    macs: &'a [String],
+}
+
+const REPORT_ATTRS: &[&str] = &[
+    "time",
+    "tx_bytes",
+    "rx_bytes",
+    "lan-num_sta",
+    "wlan-num_sta",
+];
+
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 struct ApiResponse<T> {
     meta: Meta,
+    #[serde(default = "Vec::new")]
     data: Vec<T>,
 }
 
+/// Just the `meta` envelope, to explain non-2xx responses.
+#[derive(Debug, Deserialize)]
+struct MetaOnly {
+    meta: Meta,
+}
+
 #[derive(Clone)]
 enum AuthMethod {
     ApiKey(String),
     UserPass { username: String, password: String },
 }
 
+/// Which kind of controller the exporter talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ControllerFlavor {
+    /// Classic Network Application (self-hosted, Cloud Key Gen1)
+    Classic,
+    /// UniFi OS console (UDM, UDR, UCG, Cloud Key Gen2+), which proxies the
+    /// Network API under `/proxy/network`
+    #[value(name = "unifi-os")]
+    UnifiOs,
+}
+
+impl ControllerFlavor {
+    fn login_path(&self) -> &'static str {
+        match self {
+            ControllerFlavor::Classic => "/api/login",
+            ControllerFlavor::UnifiOs => "/api/auth/login",
+        }
+    }
+
+    fn logout_path(&self) -> &'static str {
+        match self {
+            ControllerFlavor::Classic => "/api/logout",
+            ControllerFlavor::UnifiOs => "/api/auth/logout",
+        }
+    }
+
+    fn api_prefix(&self) -> &'static str {
+        match self {
+            ControllerFlavor::Classic => "",
+            ControllerFlavor::UnifiOs => "/proxy/network",
+        }
+    }
+}
+
+/// Which controller API devices and clients are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum NetworkApi {
+    /// Classic Network API (`/api/s/<site>/stat/...`)
+    #[default]
+    Legacy,
+    /// Documented Integration API (`/proxy/network/integration/v1`), API key
+    /// only; for controllers where the classic API is unavailable
+    Integration,
+}
+
+/// Log in again this long before the session expires.
+const SESSION_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+const CSRF_TOKEN: &str = "x-csrf-token";
+const UPDATED_CSRF_TOKEN: &str = "x-updated-csrf-token";
+
+/// A cookie set by the controller, without its attributes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SessionCookie {
+    name: String,
+    value: String,
+    expires_at: Option<SystemTime>,
+}
+
+/// Claims of the UniFi OS `TOKEN` JWT that matter for the session.
+#[derive(Debug, Deserialize)]
+struct TokenClaims {
+    exp: Option<u64>,
+    #[serde(rename = "csrfToken")]
+    csrf_token: Option<String>,
+}
+
+/// Decode the payload of a JWT without verifying it; the controller does
+/// that, the exporter only needs the expiry and CSRF token.
+fn token_claims(token: &str) -> Option<TokenClaims> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Expiry of a cookie from its `Max-Age` or `Expires` attribute.
+fn cookie_expiry(cookie: &Cookie<'_>) -> Option<SystemTime> {
+    if let Some(max_age) = cookie.max_age() {
+        let seconds = max_age.whole_seconds().max(0) as u64;
+        return Some(SystemTime::now() + Duration::from_secs(seconds));
+    }
+    let expires = cookie.expires_datetime()?;
+    Some(UNIX_EPOCH + Duration::from_secs(expires.unix_timestamp().max(0) as u64))
+}
+
+/// Session established by a username/password login.
+///
+/// Classic controllers use a `unifises` session cookie. UniFi OS uses a JWT in
+/// the `TOKEN` cookie and requires the `X-CSRF-Token` from the login response
+/// on every request; it rotates the token through `X-Updated-CSRF-Token`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Session {
+    cookies: Vec<SessionCookie>,
+    csrf_token: Option<String>,
+}
+
+impl Session {
+    /// Apply `Set-Cookie` and CSRF token headers from a controller response.
+    fn update(&mut self, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        for value in headers.get_all(SET_COOKIE) {
+            let Some(cookie) = value.to_str().ok().and_then(|v| Cookie::parse(v).ok()) else {
+                continue;
+            };
+            self.cookies.retain(|c| c.name != cookie.name());
+
+            let mut expires_at = cookie_expiry(&cookie);
+            if cookie.name() == "TOKEN"
+                && let Some(claims) = token_claims(cookie.value())
+            {
+                if let Some(exp) = claims.exp {
+                    expires_at = Some(UNIX_EPOCH + Duration::from_secs(exp));
+                }
+                if self.csrf_token.is_none() {
+                    self.csrf_token = claims.csrf_token;
+                }
+            }
+
+            // An empty or already expired cookie deletes it
+            if cookie.value().is_empty() || expires_at.is_some_and(|e| e <= now) {
+                continue;
+            }
+            self.cookies.push(SessionCookie {
+                name: cookie.name().to_string(),
+                value: cookie.value().to_string(),
+                expires_at,
+            });
+        }
+
+        // Prefer the rotated token over the one from the login response
+        for name in [CSRF_TOKEN, UPDATED_CSRF_TOKEN] {
+            if let Some(token) = headers.get(name).and_then(|v| v.to_str().ok()) {
+                self.csrf_token = Some(token.to_string());
+            }
+        }
+    }
+
+    /// The earliest expiry of the session cookies, if any of them expire.
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.cookies.iter().filter_map(|c| c.expires_at).min()
+    }
+
+    fn needs_refresh(&self, now: SystemTime) -> bool {
+        self.cookies.is_empty()
+            || self
+                .expires_at()
+                .is_some_and(|expires_at| now + SESSION_REFRESH_MARGIN >= expires_at)
+    }
+
+    fn cookie_header(&self) -> String {
+        self.cookies
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&self.cookie_header()) {
+            headers.insert(COOKIE, value);
+        }
+        if let Some(token) = &self.csrf_token
+            && let Ok(value) = HeaderValue::from_str(token)
+        {
+            headers.insert(CSRF_TOKEN, value);
+        }
+    }
+}
+
+/// Session persisted across restarts, tied to the controller and user it
+/// was issued for.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionCache {
+    controller_url: String,
+    username: String,
+    session: Session,
+}
+
+impl SessionCache {
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(std::io::Error::other)
+    }
+
+    /// Write the cache readable by the owner only, replacing any previous
+    /// file atomically.
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        use std::io::Write as _;
+
+        let contents = serde_json::to_vec(self).map_err(std::io::Error::other)?;
+        let tmp_path = path.with_extension("tmp");
+        let _ = std::fs::remove_file(&tmp_path);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+/// Wait after the first failed login; doubled for every further failure.
+const LOGIN_BACKOFF_BASE: Duration = Duration::from_secs(30);
+/// Longest wait between login attempts.
+const LOGIN_BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Consecutive transient login failures and when to try again.
+#[derive(Debug, Default)]
+struct LoginBackoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl LoginBackoff {
+    fn delay(failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        (LOGIN_BACKOFF_BASE * 2u32.pow(exponent)).min(LOGIN_BACKOFF_MAX)
+    }
+
+    /// Time left in the current backoff window, if any.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.retry_at
+            .filter(|retry_at| *retry_at > now)
+            .map(|retry_at| retry_at - now)
+    }
+
+    /// Start the next backoff window, honouring a controller's Retry-After.
+    fn record_failure(&mut self, error: &UniFiError, now: Instant) -> Duration {
+        self.failures += 1;
+        let mut delay = Self::delay(self.failures);
+        if let UniFiError::RateLimited {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            delay = delay.max(*retry_after);
+        }
+        self.retry_at = Some(now + delay);
+        delay
+    }
+}
+
+/// Requests in flight to the controller at once, unless configured otherwise.
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
 pub struct UniFiClient {
     client: reqwest::Client,
-    base_url: String,
+    transport: Transport,
+    /// Same TLS settings as `client` but without following redirects
+    probe_client: reqwest::Client,
+    pub(crate) base_url: String,
     auth_method: AuthMethod,
-    site: String,
-    auth_cookies: Arc<RwLock<Option<String>>>,
+    pub(crate) site: String,
+    session: RwLock<Option<Session>>,
+    session_file: Option<PathBuf>,
+    /// Held while logging in, so concurrent requests share one login
+    login_backoff: Mutex<LoginBackoff>,
+    /// Set once the controller rejects the credentials; they are not tried
+    /// again, as UniFi OS locks accounts after repeated failures
+    credentials_rejected: AtomicBool,
+    flavor: OnceCell<ControllerFlavor>,
+    /// Integration API id of the configured site, looked up once
+    pub(crate) integration_site_id: OnceCell<String>,
+    /// Integration API device ids to MAC addresses from the last device
+    /// list, to name the devices clients connect to
+    pub(crate) integration_device_macs: std::sync::Mutex<HashMap<String, String>>,
+    retry_policy: RetryPolicy,
+    circuit_breaker: CircuitBreaker,
+    /// Bounds the requests in flight to the controller
+    request_slots: Semaphore,
+    /// Timeouts overriding the client timeout, by endpoint name
+    endpoint_timeouts: HashMap<String, Duration>,
+    recorder: Option<Recorder>,
+    parse_errors: ParseErrors,
+    /// Failed requests the data was completed without, by endpoint, until
+    /// taken for the metrics
+    partial_errors: std::sync::Mutex<Vec<(String, UniFiError)>>,
+}
+
+/// Settings the HTTP clients are built from.
+#[derive(Debug, Clone)]
+struct Transport {
+    timeout: Duration,
+    verify_ssl: bool,
+    tls: TlsOptions,
+    proxy: ProxyOptions,
+    /// Address to connect to for the TLS server name override
+    resolve: Option<(String, SocketAddr)>,
+}
+
+impl Transport {
+    /// The HTTP client for API requests and one for probing the controller
+    /// that doesn't follow redirects.
+    fn build(&self) -> Result<(reqwest::Client, reqwest::Client)> {
+        let builder = || {
+            let mut builder = reqwest::Client::builder().timeout(self.timeout);
+            if let Some((name, addr)) = &self.resolve {
+                builder = builder.resolve(name, *addr);
+            }
+            let builder = proxy::configure(builder, &self.proxy)?;
+            tls::configure(builder, &self.tls, self.verify_ssl)
+        };
+        let client = builder()?.build()?;
+        let probe_client = builder()?
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok((client, probe_client))
+    }
 }
 
 /// Build a UniFi legacy (cookie-auth) API URL.
@@ -169,11 +929,14 @@ impl UniFiClient {
         timeout: Duration,
         verify_ssl: bool,
     ) -> Result<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(timeout)
-            .danger_accept_invalid_certs(!verify_ssl)
-            .cookie_store(true)
-            .build()?;
+        let transport = Transport {
+            timeout,
+            verify_ssl,
+            tls: TlsOptions::default(),
+            proxy: ProxyOptions::default(),
+            resolve: None,
+        };
+        let (client, probe_client) = transport.build()?;
 
         // Determine auth method
         let auth_method = if let Some(key) = api_key {
@@ -184,269 +947,642 @@ impl UniFiClient {
                 password: pass,
             }
         } else {
-            return Err(anyhow!(
-                "Either API key or username/password must be provided"
+            return Err(UniFiError::InvalidConfig(
+                "Either API key or username/password must be provided".to_string(),
             ));
         };
 
         Ok(Self {
             client,
+            transport,
+            probe_client,
             base_url: base_url.trim_end_matches('/').to_string(),
             auth_method,
             site,
-            auth_cookies: Arc::new(RwLock::new(None)),
+            session: RwLock::new(None),
+            session_file: None,
+            login_backoff: Mutex::new(LoginBackoff::default()),
+            credentials_rejected: AtomicBool::new(false),
+            flavor: OnceCell::new(),
+            integration_site_id: OnceCell::new(),
+            integration_device_macs: std::sync::Mutex::default(),
+            retry_policy: RetryPolicy::none(),
+            circuit_breaker: CircuitBreaker::disabled(),
+            request_slots: Semaphore::new(DEFAULT_MAX_CONCURRENT_REQUESTS),
+            endpoint_timeouts: HashMap::new(),
+            recorder: None,
+            parse_errors: ParseErrors::default(),
+            partial_errors: std::sync::Mutex::default(),
         })
     }
 
-    pub async fn ensure_authenticated(&self) -> Result<()> {
-        match &self.auth_method {
-            AuthMethod::ApiKey(_) => Ok(()), // API key doesn't need login
-            AuthMethod::UserPass { .. } => {
-                let cookies = self.auth_cookies.read().await;
-                if cookies.is_some() {
-                    return Ok(());
-                }
-                drop(cookies);
-                self.login().await
-            }
+    /// Connect with a custom CA bundle, certificate pinning, a client
+    /// certificate or a TLS server name override.
+    pub fn with_tls(mut self, tls: &TlsOptions) -> Result<Self> {
+        if let Some(server_name) = &tls.server_name {
+            let (base_url, addr) = tls::override_server_name(&self.base_url, server_name)?;
+            self.base_url = base_url;
+            self.transport.resolve = Some((server_name.clone(), addr));
         }
+        self.transport.tls = tls.clone();
+        (self.client, self.probe_client) = self.transport.build()?;
+        Ok(self)
     }
 
-    async fn login(&self) -> Result<()> {
-        match &self.auth_method {
-            AuthMethod::ApiKey(_) => Ok(()), // No login needed for API key
-            AuthMethod::UserPass { username, password } => {
-                let login_url = format!("{}/api/login", self.base_url);
-                let login_data = LoginRequest {
-                    username: username.clone(),
-                    password: password.clone(),
-                    remember: false,
-                };
-
-                let response = self
-                    .client
-                    .post(&login_url)
-                    .json(&login_data)
-                    .send()
-                    .await?;
-
-                if !response.status().is_success() {
-                    return Err(anyhow!("Login failed with status: {}", response.status()));
-                }
+    /// Reach the controller through an HTTP or SOCKS5 proxy instead of the
+    /// proxies from the `HTTPS_PROXY`/`NO_PROXY` environment variables.
+    pub fn with_proxy(mut self, proxy: &ProxyOptions) -> Result<Self> {
+        self.transport.proxy = proxy.clone();
+        (self.client, self.probe_client) = self.transport.build()?;
+        Ok(self)
+    }
 
-                // Extract cookies from response
-                let cookies: Vec<String> = response
-                    .headers()
-                    .get_all("set-cookie")
-                    .iter()
-                    .filter_map(|value| value.to_str().ok())
-                    .map(|s| s.to_string())
-                    .collect();
-
-                if cookies.is_empty() {
-                    return Err(anyhow!("No cookies received from login response"));
-                }
+    /// Limit how many requests are sent to the controller at once.
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.request_slots = Semaphore::new(max.max(1));
+        self
+    }
 
-                let cookie_string = cookies.join("; ");
-                *self.auth_cookies.write().await = Some(cookie_string);
+    /// Per-endpoint timeouts (`login`, `stat/device`, `stat/sta`, `sites`,
+    /// ...) overriding the client timeout.
+    pub fn with_endpoint_timeouts(mut self, timeouts: HashMap<String, Duration>) -> Self {
+        self.endpoint_timeouts = timeouts;
+        self
+    }
 
-                Ok(())
-            }
-        }
+    /// Write every successful response to disk, redacted.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
     }
 
-    async fn get_legacy<T>(&self, path: &str, site_scoped: bool) -> Result<Vec<T>>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let url = match &self.auth_method {
-            AuthMethod::ApiKey(_) => {
-                // API key uses different URL pattern
-                format!(
-                    "{}/proxy/network/integration/v1/{}",
-                    self.base_url,
-                    path.trim_start_matches('/')
-                )
-            }
-            AuthMethod::UserPass { .. } => {
-                // Cookie auth uses traditional API path. Site-scoped endpoints
-                // live under /api/s/<site>/, controller-global endpoints like
-                // /self/sites live under /api/ directly.
-                legacy_url(&self.base_url, &self.site, path, site_scoped)
-            }
-        };
+    /// Retry GET requests that fail transiently.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-        debug!("Making request to: {}", url);
+    /// Stop contacting a controller that keeps failing.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
 
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    /// Skip detection and talk to the controller as the given flavor.
+    pub fn with_controller_flavor(self, flavor: ControllerFlavor) -> Self {
+        let _ = self.flavor.set(flavor);
+        self
+    }
 
-        match &self.auth_method {
-            AuthMethod::ApiKey(key) => {
-                headers.insert("X-API-KEY", HeaderValue::from_str(key).unwrap());
-            }
-            AuthMethod::UserPass { .. } => {
-                if let Some(cookies) = &*self.auth_cookies.read().await {
-                    headers.insert(COOKIE, HeaderValue::from_str(cookies).unwrap());
+    /// Persist the login session in `path` and reuse it on startup, so
+    /// restarts don't log in again while the session is still valid.
+    pub fn with_session_file(mut self, path: PathBuf) -> Self {
+        if let AuthMethod::UserPass { username, .. } = &self.auth_method {
+            match SessionCache::load(&path) {
+                Ok(cache)
+                    if cache.controller_url == self.base_url && cache.username == *username =>
+                {
+                    if cache.session.needs_refresh(SystemTime::now()) {
+                        debug!("Cached session in {} has expired", path.display());
+                    } else {
+                        info!("Reusing cached session from {}", path.display());
+                        *self.session.get_mut() = Some(cache.session);
+                    }
                 }
+                Ok(_) => debug!(
+                    "Cached session in {} is for another controller or user",
+                    path.display()
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Ignoring session cache {}: {}", path.display(), e),
             }
         }
+        self.session_file = Some(path);
+        self
+    }
 
-        let response = self.client.get(&url).headers(headers).send().await?;
-
-        if response.status() == 401 && matches!(&self.auth_method, AuthMethod::UserPass { .. }) {
-            // Try to re-authenticate
-            drop(self.auth_cookies.write().await.take());
-            self.login()
-                .await
-                .map_err(|_| UniFiError::AuthenticationFailed)?;
+    /// Whether requests authenticate with an API key rather than a session.
+    pub fn uses_api_key(&self) -> bool {
+        matches!(self.auth_method, AuthMethod::ApiKey(_))
+    }
 
-            // Retry request
-            let mut headers = HeaderMap::new();
-            headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-            if let Some(cookies) = &*self.auth_cookies.read().await {
-                headers.insert(COOKIE, HeaderValue::from_str(cookies).unwrap());
-            }
+    /// Whether sessions are persisted, in which case they should outlive
+    /// the process rather than be logged out.
+    pub fn has_session_file(&self) -> bool {
+        self.session_file.is_some()
+    }
 
-            let response = self.client.get(&url).headers(headers).send().await?;
+    fn save_session(&self, session: &Session) {
+        let (Some(path), AuthMethod::UserPass { username, .. }) =
+            (&self.session_file, &self.auth_method)
+        else {
+            return;
+        };
+        let cache = SessionCache {
+            controller_url: self.base_url.clone(),
+            username: username.clone(),
+            session: session.clone(),
+        };
+        if let Err(e) = cache.save(path) {
+            warn!("Failed to write session cache {}: {}", path.display(), e);
+        }
+    }
 
-            if !response.status().is_success() {
-                return Err(UniFiError::ParseError(format!(
-                    "API request failed with status: {}",
-                    response.status()
-                ))
-                .into());
+    /// The controller flavor, detected on first use and cached.
+    ///
+    /// API keys only exist on UniFi OS, so no probing is needed for them.
+    pub async fn controller_flavor(&self) -> Result<ControllerFlavor> {
+        self.flavor
+            .get_or_try_init(|| async {
+                let flavor = match &self.auth_method {
+                    AuthMethod::ApiKey(_) => ControllerFlavor::UnifiOs,
+                    AuthMethod::UserPass { .. } => self.detect_flavor().await?,
+                };
+                info!("Controller type: {:?}", flavor);
+                Ok(flavor)
+            })
+            .await
+            .copied()
+    }
+
+    pub(crate) async fn detect_flavor(&self) -> Result<ControllerFlavor> {
+        let probe = |path: &str| {
+            let url = format!("{}{path}", self.base_url);
+            async move {
+                self.probe_client
+                    .get(&url)
+                    .send()
+                    .await
+                    .map_err(|e| UniFiError::from_reqwest(&url, e))
             }
+        };
 
-            let api_response: ApiResponse<T> = response.json().await?;
-            Ok(api_response.data)
-        } else if response.status().is_success() {
-            let api_response: ApiResponse<T> = response.json().await?;
-            Ok(api_response.data)
+        // The Network application's unauthenticated status endpoint is
+        // proxied on UniFi OS and served at the root on classic controllers
+        if probe("/proxy/network/status").await?.status() == StatusCode::OK {
+            return Ok(ControllerFlavor::UnifiOs);
+        }
+        if probe("/status").await?.status() == StatusCode::OK {
+            return Ok(ControllerFlavor::Classic);
+        }
+
+        // UniFi OS serves its web UI at `/`, classic controllers redirect to
+        // `/manage`
+        let root = probe("/").await?;
+        if root.status() == StatusCode::OK {
+            Ok(ControllerFlavor::UnifiOs)
         } else {
-            Err(UniFiError::ParseError(format!(
-                "API request failed with status: {}",
-                response.status()
-            ))
-            .into())
+            debug!(
+                "Root returned {}, assuming a classic controller",
+                root.status()
+            );
+            Ok(ControllerFlavor::Classic)
         }
     }
 
-    pub async fn get_devices(&self) -> Result<Vec<Device>> {
+    /// Log in unless there is a session that is not about to expire.
+    ///
+    /// Failed logins back off exponentially, so at most one attempt is made
+    /// per backoff window. Rejected credentials are never retried.
+    pub async fn ensure_authenticated(&self) -> Result<()> {
         match &self.auth_method {
-            AuthMethod::ApiKey(key) => {
-                // Use the regular API with API key authentication for full metrics
-                let url = format!(
-                    "{}/proxy/network/api/s/{}/stat/device",
-                    self.base_url, self.site
-                );
-
-                debug!("Making request to: {}", url);
-
-                let mut headers = HeaderMap::new();
-                headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-                headers.insert("X-API-KEY", HeaderValue::from_str(key)?);
-
-                let response = self.client.get(&url).headers(headers).send().await?;
-
-                if !response.status().is_success() {
-                    return Err(anyhow!("API request failed: {}", response.status()));
+            AuthMethod::ApiKey(_) => Ok(()), // API key doesn't need login
+            AuthMethod::UserPass { .. } => {
+                if self.session_is_fresh().await {
+                    return Ok(());
                 }
-
-                #[derive(Debug, Deserialize)]
-                struct ApiResponse {
-                    #[allow(dead_code)]
-                    meta: Meta,
-                    data: Vec<Device>,
+                let mut backoff = self.login_backoff.lock().await;
+                // Another request may have logged in while we waited
+                if self.session_is_fresh().await {
+                    return Ok(());
+                }
+                if self.credentials_rejected() {
+                    return Err(UniFiError::AuthenticationFailed);
+                }
+                if let Some(retry_in) = backoff.remaining(Instant::now()) {
+                    return Err(UniFiError::LoginBackoff { retry_in });
                 }
 
-                let text = response.text().await?;
-                match serde_json::from_str::<ApiResponse>(&text) {
-                    Ok(api_response) => Ok(api_response.data),
-                    Err(e) => {
-                        eprintln!("Failed to parse device JSON: {e}");
-                        eprintln!(
-                            "Response text (first 500 chars): {}",
-                            &text.chars().take(500).collect::<String>()
+                match self.login().await {
+                    Ok(()) => {
+                        *backoff = LoginBackoff::default();
+                        Ok(())
+                    }
+                    Err(UniFiError::AuthenticationFailed) => {
+                        error!(
+                            "Controller rejected the credentials, not logging in again; fix them and restart the exporter"
                         );
-                        Err(anyhow!("Failed to parse device response: {e}"))
+                        self.credentials_rejected.store(true, Ordering::Relaxed);
+                        Err(UniFiError::AuthenticationFailed)
+                    }
+                    Err(e) => {
+                        let delay = backoff.record_failure(&e, Instant::now());
+                        warn!("Login failed ({}), next attempt in {:?}", e.kind(), delay);
+                        Err(e)
                     }
                 }
             }
-            AuthMethod::UserPass { .. } => self.get_legacy("stat/device", true).await,
         }
     }
 
-    pub async fn get_clients(&self) -> Result<Vec<Client>> {
-        match &self.auth_method {
-            AuthMethod::ApiKey(key) => {
-                // Use the regular API with API key authentication for full metrics
-                let url = format!(
-                    "{}/proxy/network/api/s/{}/stat/sta",
-                    self.base_url, self.site
-                );
+    /// Whether the controller rejected the configured username/password.
+    pub fn credentials_rejected(&self) -> bool {
+        self.credentials_rejected.load(Ordering::Relaxed)
+    }
 
-                debug!("Making request to: {}", url);
+    /// Records skipped because they failed to decode.
+    pub fn parse_errors(&self) -> &ParseErrors {
+        &self.parse_errors
+    }
 
-                let mut headers = HeaderMap::new();
-                headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-                headers.insert("X-API-KEY", HeaderValue::from_str(key)?);
+    /// Note a failed request that the caller works around, so it is still
+    /// counted.
+    pub(crate) fn record_partial_error(&self, endpoint: &str, error: UniFiError) {
+        warn!(endpoint, kind = error.kind(), "Request failed: {}", error);
+        self.partial_errors
+            .lock()
+            .unwrap()
+            .push((endpoint.to_string(), error));
+    }
 
-                let response = self.client.get(&url).headers(headers).send().await?;
+    /// Requests that failed since the last call without failing the data
+    /// they were for.
+    pub fn take_partial_errors(&self) -> Vec<(String, UniFiError)> {
+        std::mem::take(&mut *self.partial_errors.lock().unwrap())
+    }
 
-                if !response.status().is_success() {
-                    return Err(anyhow!("API request failed: {}", response.status()));
-                }
+    /// Record data fetched some other way as the classic API response for
+    /// `path`, so that the capture replays.
+    pub(crate) fn record_converted<T: Serialize>(&self, path: &str, data: &[T]) {
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+        match serde_json::to_value(data) {
+            Ok(data) => recorder.record_value(
+                path,
+                serde_json::json!({"meta": {"rc": "ok"}, "data": data}),
+            ),
+            Err(e) => warn!("Failed to record {}: {}", path, e),
+        }
+    }
 
-                #[derive(Debug, Deserialize)]
-                struct ApiResponse {
-                    #[allow(dead_code)]
-                    meta: Meta,
-                    data: Vec<Client>,
-                }
+    async fn session_is_fresh(&self) -> bool {
+        match &*self.session.read().await {
+            Some(session) if session.needs_refresh(SystemTime::now()) => {
+                debug!("Session is about to expire, logging in again");
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
 
-                let text = response.text().await?;
-                match serde_json::from_str::<ApiResponse>(&text) {
-                    Ok(api_response) => Ok(api_response.data),
-                    Err(e) => {
-                        eprintln!("Failed to parse client JSON: {e}");
-                        eprintln!(
-                            "Response text (first 500 chars): {}",
-                            &text.chars().take(500).collect::<String>()
-                        );
-                        Err(anyhow!("Failed to parse client response: {e}"))
+    async fn login(&self) -> Result<()> {
+        match &self.auth_method {
+            AuthMethod::ApiKey(_) => Ok(()), // No login needed for API key
+            AuthMethod::UserPass { username, password } => {
+                let flavor = self.controller_flavor().await?;
+                let login_url = format!("{}{}", self.base_url, flavor.login_path());
+                let login_data = LoginRequest {
+                    username: username.clone(),
+                    password: password.clone(),
+                    remember: false,
+                };
+
+                let mut request = self.client.post(&login_url).json(&login_data);
+                if let Some(timeout) = self.endpoint_timeouts.get("login") {
+                    request = request.timeout(*timeout);
+                }
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| UniFiError::from_reqwest(&login_url, e))?;
+
+                match response.status() {
+                    // The classic controller answers bad credentials with 400
+                    StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                        warn!("Login rejected with status {}", response.status());
+                        return Err(UniFiError::AuthenticationFailed);
+                    }
+                    status if !status.is_success() => {
+                        return Err(UniFiError::from_status(
+                            &login_url,
+                            status,
+                            response.headers(),
+                        ));
                     }
+                    _ => {}
                 }
+
+                let mut session = Session::default();
+                session.update(response.headers());
+                if session.cookies.is_empty() {
+                    return Err(UniFiError::ParseError(
+                        "No cookies received from login response".to_string(),
+                    ));
+                }
+                if flavor == ControllerFlavor::UnifiOs && session.csrf_token.is_none() {
+                    warn!("No CSRF token received from UniFi OS login");
+                }
+                match session.expires_at() {
+                    Some(expires_at) => debug!(
+                        "Logged in, session valid for {:?}",
+                        expires_at
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default()
+                    ),
+                    None => debug!("Logged in"),
+                }
+
+                self.save_session(&session);
+                *self.session.write().await = Some(session);
+
+                Ok(())
             }
-            AuthMethod::UserPass { .. } => self.get_legacy("stat/sta", true).await,
         }
     }
 
-    pub async fn get_sites(&self) -> Result<Vec<Site>> {
+    /// End the controller session, if there is one.
+    pub async fn logout(&self) -> Result<()> {
+        let Some(session) = self.session.write().await.take() else {
+            return Ok(());
+        };
+        if let Some(path) = &self.session_file {
+            let _ = std::fs::remove_file(path);
+        }
+        let flavor = self.controller_flavor().await?;
+        let url = format!("{}{}", self.base_url, flavor.logout_path());
+
+        let mut headers = HeaderMap::new();
+        session.apply(&mut headers);
+        let response = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|e| UniFiError::from_reqwest(&url, e))?;
+        if !response.status().is_success() {
+            return Err(UniFiError::from_status(
+                &url,
+                response.status(),
+                response.headers(),
+            ));
+        }
+
+        info!("Logged out of the controller");
+        Ok(())
+    }
+
+    /// URL of a classic Network API endpoint, under `/proxy/network` on
+    /// UniFi OS.
+    async fn api_url(&self, path: &str, site_scoped: bool) -> Result<String> {
+        let flavor = self.controller_flavor().await?;
+        let base_url = format!("{}{}", self.base_url, flavor.api_prefix());
+        Ok(legacy_url(&base_url, &self.site, path, site_scoped))
+    }
+
+    async fn auth_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
         match &self.auth_method {
-            AuthMethod::ApiKey(_) => {
-                let url = format!("{}/proxy/network/integration/v1/sites", self.base_url);
+            AuthMethod::ApiKey(key) => {
+                let value = HeaderValue::from_str(key).map_err(|_| {
+                    UniFiError::InvalidConfig("API key contains invalid characters".to_string())
+                })?;
+                headers.insert("X-API-KEY", value);
+            }
+            AuthMethod::UserPass { .. } => {
+                if let Some(session) = &*self.session.read().await {
+                    session.apply(&mut headers);
+                }
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Send a request and return the body of a successful response.
+    ///
+    /// GET requests that fail transiently are retried according to the retry
+    /// policy. Requests are not sent at all while the circuit breaker is
+    /// open.
+    pub(crate) async fn send(
+        &self,
+        endpoint: &str,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        if let Err(retry_in) = self.circuit_breaker.check(Instant::now()) {
+            return Err(UniFiError::CircuitOpen { retry_in });
+        }
+
+        let max_retries = if method == reqwest::Method::GET {
+            self.retry_policy.max_retries
+        } else {
+            0
+        };
+        let mut attempt = 0;
+        let result = loop {
+            let permit = self
+                .request_slots
+                .acquire()
+                .await
+                .expect("request semaphore is never closed");
+            let result = self.send_once(endpoint, method.clone(), url, body).await;
+            drop(permit);
+            match result {
+                Err(e) if e.is_transient() && attempt < max_retries => {
+                    attempt += 1;
+                    let Some(delay) = self.retry_policy.delay(attempt, &e) else {
+                        break Err(e);
+                    };
+                    debug!(
+                        "Request to {} failed ({}), retry {}/{} in {:?}",
+                        url,
+                        e.kind(),
+                        attempt,
+                        max_retries,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => break result,
+            }
+        };
 
-                debug!("Making request to: {}", url);
+        if let (Ok(body), Some(recorder)) = (&result, &self.recorder) {
+            recorder.record(&record_path(url, &self.base_url, &self.site), body);
+        }
 
-                let mut headers = HeaderMap::new();
-                headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-                if let AuthMethod::ApiKey(key) = &self.auth_method {
-                    headers.insert("X-API-KEY", HeaderValue::from_str(key).unwrap());
+        match &result {
+            Err(e) if e.is_transient() => {
+                if self.circuit_breaker.record_failure(Instant::now()) {
+                    warn!("Controller keeps failing, pausing requests to it");
                 }
+            }
+            // Any answer, even an error status, means the controller is up
+            _ => {
+                if self.circuit_breaker.record_success() {
+                    info!("Controller is responding again");
+                }
+            }
+        }
+        result
+    }
 
-                let response = self.client.get(&url).headers(headers).send().await?;
+    /// Send a request once, apart from renewing the session.
+    ///
+    /// With cookie auth the session is renewed before it expires, and on a
+    /// 401 it is renewed and the request is sent once more.
+    async fn send_once(
+        &self,
+        endpoint: &str,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let cookie_auth = matches!(&self.auth_method, AuthMethod::UserPass { .. });
+        let mut retried = false;
+        loop {
+            debug!("Making request to: {}", url);
+
+            self.ensure_authenticated().await?;
+            let headers = self.auth_headers().await?;
+            let sent_cookies = headers.get(COOKIE).cloned();
+
+            let mut request = self.client.request(method.clone(), url).headers(headers);
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+            if let Some(timeout) = self.endpoint_timeouts.get(endpoint) {
+                request = request.timeout(*timeout);
+            }
 
-                if !response.status().is_success() {
-                    return Err(anyhow!("API request failed: {}", response.status()));
+            let response = request
+                .send()
+                .await
+                .map_err(|e| UniFiError::from_reqwest(url, e))?;
+            let status = response.status();
+
+            if cookie_auth && status == StatusCode::UNAUTHORIZED && !retried {
+                // Session expired: drop it (unless another request already
+                // replaced it) so the next attempt logs in again
+                debug!("Session expired, logging in again");
+                let mut session = self.session.write().await;
+                if session.as_ref().map(|s| s.cookie_header())
+                    == sent_cookies.and_then(|v| v.to_str().ok().map(str::to_string))
+                {
+                    *session = None;
                 }
+                retried = true;
+                continue;
+            }
 
-                let api_response: IntegrationResponse<IntegrationSite> = response.json().await?;
-                Ok(api_response.data.into_iter().map(|s| s.to_site()).collect())
+            if cookie_auth && let Some(session) = self.session.write().await.as_mut() {
+                let previous = session.clone();
+                session.update(response.headers());
+                if *session != previous {
+                    self.save_session(session);
+                }
             }
-            AuthMethod::UserPass { .. } => self.get_legacy("self/sites", false).await,
+
+            if status.is_success() {
+                return response
+                    .text()
+                    .await
+                    .map_err(|e| UniFiError::from_reqwest(url, e));
+            }
+
+            let error = UniFiError::from_status(url, status, response.headers());
+            // The classic API explains client errors (e.g. an unknown site)
+            // in the `meta` envelope
+            if matches!(error, UniFiError::HttpStatus { .. })
+                && let Ok(body) = response.text().await
+                && let Ok(envelope) = serde_json::from_str::<MetaOnly>(&body)
+            {
+                envelope.meta.check(endpoint)?;
+            }
+            return Err(error);
         }
     }
+
+    async fn get_legacy<T>(&self, path: &str, site_scoped: bool) -> Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.get_legacy_endpoint(path, path, site_scoped).await
+    }
+
+    /// Like [`Self::get_legacy`], for endpoints whose name differs from
+    /// their path.
+    async fn get_legacy_endpoint<T>(
+        &self,
+        endpoint: &str,
+        path: &str,
+        site_scoped: bool,
+    ) -> Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let url = self.api_url(path, site_scoped).await?;
+        let body = self
+            .send(endpoint, reqwest::Method::GET, &url, None)
+            .await?;
+        decode_legacy(endpoint, &body, &self.parse_errors)
+    }
+
+    async fn post_legacy<B, T>(&self, path: &str, body: &B) -> Result<Vec<T>>
+    where
+        B: Serialize,
+        T: serde::de::DeserializeOwned,
+    {
+        let url = self.api_url(path, true).await?;
+        let body = serde_json::to_value(body).map_err(|e| UniFiError::ParseError(e.to_string()))?;
+        let body = self
+            .send(path, reqwest::Method::POST, &url, Some(&body))
+            .await?;
+        decode_legacy(path, &body, &self.parse_errors)
+    }
+
+    /// Fetch historical report data (`stat/report/<interval>.<report_type>`)
+    /// between `start_ms` and `end_ms` (milliseconds since the Unix epoch).
+    ///
+    /// `macs` restricts `user` reports to the given clients; it is ignored
+    /// when empty.
+    pub async fn get_report(
+        &self,
+        interval: ReportInterval,
+        report_type: &str,
+        start_ms: i64,
+        end_ms: i64,
+        macs: &[String],
+    ) -> Result<Vec<ReportEntry>> {
+        let path = format!("stat/report/{}.{}", interval.as_str(), report_type);
+        let request = ReportRequest {
+            attrs: REPORT_ATTRS,
+            start: start_ms,
+            end: end_ms,
+            macs,
+        };
+        self.post_legacy(&path, &request).await
+    }
+
+    pub async fn get_devices(&self) -> Result<Vec<Device>> {
+        self.get_legacy("stat/device", true).await
+    }
+
+    pub async fn get_clients(&self) -> Result<Vec<Client>> {
+        self.get_legacy("stat/sta", true).await
+    }
+
+    /// Sites visible to the logged-in admin. API keys are not accepted
+    /// here; their sites come from the Integration API.
+    pub async fn get_legacy_sites(&self) -> Result<Vec<Site>> {
+        self.get_legacy_endpoint("sites", "self/sites", false).await
+    }
 }
 
 #[cfg(test)]
@@ -576,6 +1712,101 @@ mod tests {
         assert!(device.adopted);
         assert_eq!(device.state, 1);
         assert_eq!(device.uptime, Some(86400));
+        assert!(!device.upgradable);
+        assert_eq!(device.upgrade_to_firmware, None);
+        assert!(device.lldp_table.is_empty());
+    }
+
+    #[test]
+    fn test_device_deserialize_uplink() {
+        let json = r#"{
+            "_id": "device123",
+            "mac": "00:11:22:33:44:55",
+            "type": "uap",
+            "uplink_depth": 2,
+            "uplink": {
+                "type": "wireless",
+                "uplink_mac": "00:11:22:33:44:66",
+                "speed": 867,
+                "full_duplex": true,
+                "signal": -58,
+                "rssi": 38
+            },
+            "last_uplink": {
+                "type": "wire",
+                "uplink_mac": "00:11:22:33:44:77",
+                "uplink_remote_port": 7
+            }
+        }"#;
+        let device: Device = serde_json::from_str(json).unwrap();
+        assert_eq!(device.uplink_depth, Some(2));
+        let uplink = device.uplink.unwrap();
+        assert_eq!(uplink.uplink_type, Some("wireless".to_string()));
+        assert_eq!(uplink.uplink_mac, Some("00:11:22:33:44:66".to_string()));
+        assert_eq!(uplink.uplink_remote_port, None);
+        assert_eq!(uplink.speed, Some(867));
+        assert_eq!(uplink.full_duplex, Some(true));
+        assert_eq!(uplink.signal, Some(-58));
+        let last_uplink = device.last_uplink.unwrap();
+        assert_eq!(last_uplink.uplink_type, Some("wire".to_string()));
+        assert_eq!(last_uplink.uplink_remote_port, Some(7));
+    }
+
+    #[test]
+    fn test_device_deserialize_lldp_table() {
+        let json = r#"{
+            "_id": "device123",
+            "mac": "00:11:22:33:44:55",
+            "type": "usw",
+            "lldp_table": [
+                {
+                    "local_port_idx": 24,
+                    "local_port_name": "Port 24",
+                    "chassis_id": "aa:bb:cc:00:11:22",
+                    "port_id": "ge-0/0/1",
+                    "system_name": "fw01",
+                    "is_wired": true
+                },
+                {
+                    "local_port_idx": 3,
+                    "chassis_id": "aa:bb:cc:00:11:33",
+                    "port_id": "eth0"
+                }
+            ]
+        }"#;
+        let device: Device = serde_json::from_str(json).unwrap();
+        assert_eq!(device.lldp_table.len(), 2);
+        assert_eq!(device.lldp_table[0].local_port_idx, Some(24));
+        assert_eq!(
+            device.lldp_table[0].local_port_name,
+            Some("Port 24".to_string())
+        );
+        assert_eq!(
+            device.lldp_table[0].chassis_id,
+            Some("aa:bb:cc:00:11:22".to_string())
+        );
+        assert_eq!(device.lldp_table[0].port_id, Some("ge-0/0/1".to_string()));
+        assert_eq!(device.lldp_table[0].system_name, Some("fw01".to_string()));
+        assert_eq!(device.lldp_table[1].system_name, None);
+    }
+
+    #[test]
+    fn test_device_deserialize_upgrade_fields() {
+        let json = r#"{
+            "_id": "device123",
+            "mac": "00:11:22:33:44:55",
+            "type": "usw",
+            "version": "6.5.59.14777",
+            "upgradable": true,
+            "upgrade_to_firmware": "7.0.50.15116",
+            "required_version": "5.43.0",
+            "upgrade_state": 0
+        }"#;
+        let device: Device = serde_json::from_str(json).unwrap();
+        assert!(device.upgradable);
+        assert_eq!(device.upgrade_to_firmware, Some("7.0.50.15116".to_string()));
+        assert_eq!(device.required_version, Some("5.43.0".to_string()));
+        assert_eq!(device.upgrade_state, Some(0));
     }
 
     #[test]
@@ -611,6 +1842,22 @@ mod tests {
         assert_eq!(client.uptime, Some(3600));
         assert!(!client.is_wired);
         assert!(!client.is_guest);
+        assert_eq!(client.sw_mac, None);
+    }
+
+    #[test]
+    fn test_client_deserialize_wired_port() {
+        let json = r#"{
+            "_id": "client456",
+            "mac": "aa:bb:cc:dd:ee:01",
+            "sw_mac": "00:11:22:33:44:66",
+            "sw_port": 12,
+            "is_wired": true
+        }"#;
+        let client: Client = serde_json::from_str(json).unwrap();
+        assert_eq!(client.sw_mac, Some("00:11:22:33:44:66".to_string()));
+        assert_eq!(client.sw_port, Some(12));
+        assert!(client.is_wired);
     }
 
     #[test]
@@ -661,6 +1908,23 @@ mod tests {
         assert_eq!(stats.mem_used, Some(536870912));
     }
 
+    #[test]
+    fn test_sys_stats_round_trip() {
+        let stats = SysStats {
+            loadavg_1: Some(0.25),
+            loadavg_5: None,
+            loadavg_15: Some(1.0),
+            mem_total: None,
+            mem_used: None,
+        };
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["loadavg_1"], "0.25");
+        let stats: SysStats = serde_json::from_value(json).unwrap();
+        assert_eq!(stats.loadavg_1, Some(0.25));
+        assert_eq!(stats.loadavg_5, None);
+        assert_eq!(stats.loadavg_15, Some(1.0));
+    }
+
     #[test]
     fn test_device_stats_deserialize() {
         let json = r#"{
@@ -678,6 +1942,1075 @@ mod tests {
         assert_eq!(stats.rx_packets, Some(2000));
     }
 
+    #[test]
+    fn test_report_entry_deserialize() {
+        let json = r#"{
+            "time": 1700000000000,
+            "ap": "00:11:22:33:44:55",
+            "tx_bytes": 1024.5,
+            "rx_bytes": 2048,
+            "wlan-num_sta": 2,
+            "lan-num_sta": 1,
+            "o": "ap"
+        }"#;
+        let entry: ReportEntry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry.time, 1_700_000_000_000);
+        assert_eq!(entry.ap, Some("00:11:22:33:44:55".to_string()));
+        assert_eq!(entry.user, None);
+        assert_eq!(entry.tx_bytes, Some(1024.5));
+        assert_eq!(entry.rx_bytes, Some(2048.0));
+        assert_eq!(entry.wlan_num_sta, Some(2.0));
+        assert_eq!(entry.lan_num_sta, Some(1.0));
+    }
+
+    #[test]
+    fn test_report_request_serialize() {
+        let request = ReportRequest {
+            attrs: &["time", "tx_bytes"],
+            start: 1,
+            end: 2,
+            macs: &[],
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"attrs":["time","tx_bytes"],"start":1,"end":2}"#
+        );
+
+        let macs = vec!["aa:bb:cc:dd:ee:ff".to_string()];
+        let request = ReportRequest {
+            attrs: &["time"],
+            start: 1,
+            end: 2,
+            macs: &macs,
+        };
+        assert!(
+            serde_json::to_string(&request)
+                .unwrap()
+                .contains(r#""macs":["aa:bb:cc:dd:ee:ff"]"#)
+        );
+    }
+
+    async fn api_key_client(server: &wiremock::MockServer) -> UniFiClient {
+        UniFiClient::new(
+            server.uri(),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(2),
+            true,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_classification_by_status() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let client = api_key_client(&server).await;
+
+        for (status, kind) in [
+            (401, "unauthorized"),
+            (403, "forbidden"),
+            (404, "not_found"),
+            (502, "server_error"),
+            (418, "http_status"),
+        ] {
+            server.reset().await;
+            Mock::given(method("GET"))
+                .and(path("/proxy/network/api/s/default/stat/device"))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+
+            let err = client.get_devices().await.unwrap_err();
+            assert_eq!(err.kind(), kind, "HTTP {status}: {err}");
+            assert_eq!(err.status(), Some(status));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_rate_limited_retry_after() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .mount(&server)
+            .await;
+
+        let err = api_key_client(&server)
+            .await
+            .get_clients()
+            .await
+            .unwrap_err();
+        match err {
+            UniFiError::RateLimited { retry_after, .. } => {
+                assert_eq!(retry_after, Some(Duration::from_secs(30)))
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_717);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        // 1994-11-06T08:49:37Z is 60 seconds after `now`
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:47:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_error_rate_limited_retry_after_date() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let retry_at = SystemTime::now() + Duration::from_secs(300);
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("Retry-After", httpdate::fmt_http_date(retry_at)),
+            )
+            .mount(&server)
+            .await;
+
+        let err = api_key_client(&server)
+            .await
+            .get_clients()
+            .await
+            .unwrap_err();
+        let retry_after = err.retry_after().unwrap();
+        assert!(
+            retry_after > Duration::from_secs(290) && retry_after <= Duration::from_secs(300),
+            "{retry_after:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_skips_bad_records() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"meta":{"rc":"ok"},"data":[
+                    {"_id":"d1","mac":"00:11:22:33:44:55","type":"uap","sys_stats":{"loadavg_1":0.5}},
+                    {"_id":"d2","mac":"00:11:22:33:44:56","type":"usw"},
+                    {"_id":"d3","mac":"00:11:22:33:44:57","type":"uap","sys_stats":{"loadavg_1":1.5}}
+                ]}"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server).await;
+        let devices = client.get_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0]._id, "d2");
+        assert_eq!(
+            client.parse_errors().take(),
+            BTreeMap::from([(
+                ("stat/device".to_string(), "sys_stats.loadavg_1".to_string()),
+                2
+            )])
+        );
+        // Taken once
+        assert!(client.parse_errors().take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_error_decode_reports_field_path() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // The envelope itself must still decode
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"meta":{"rc":"ok"},"data":{"_id":"d1"}}"#),
+            )
+            .mount(&server)
+            .await;
+
+        let err = api_key_client(&server)
+            .await
+            .get_devices()
+            .await
+            .unwrap_err();
+        match err {
+            UniFiError::Decode { endpoint, path, .. } => {
+                assert_eq!(endpoint, "stat/device");
+                assert_eq!(path, "data");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_field_name() {
+        assert_eq!(
+            field_name("sys_stats.loadavg_1", "invalid type"),
+            "sys_stats.loadavg_1"
+        );
+        assert_eq!(
+            field_name("port_table[3].speed", "invalid type"),
+            "port_table.speed"
+        );
+        assert_eq!(field_name(".", "missing field `mac`"), "mac");
+        assert_eq!(
+            field_name("uplink", "missing field `uplink_mac`"),
+            "uplink.uplink_mac"
+        );
+        assert_eq!(field_name(".", "invalid type: string"), ".");
+    }
+
+    #[tokio::test]
+    async fn test_error_controller_rc() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"meta":{"rc":"error","msg":"api.err.NoSiteContext"},"data":[]}"#,
+            ))
+            .mount(&server)
+            .await;
+        let err = api_key_client(&server)
+            .await
+            .get_devices()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "controller");
+        assert!(err.to_string().contains("api.err.NoSiteContext"));
+        assert!(
+            matches!(&err, UniFiError::Controller { endpoint, .. } if endpoint == "stat/device"),
+            "{err}"
+        );
+
+        // The classic controller reports an unknown site as HTTP 400 with
+        // the reason in the envelope
+        server.reset().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(
+                r#"{"meta":{"rc":"error","msg":"api.err.NoSiteContext"},"data":[]}"#,
+            ))
+            .mount(&server)
+            .await;
+        let err = api_key_client(&server)
+            .await
+            .get_devices()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "controller");
+        // Named by endpoint, as for a 200 response, not by URL
+        assert!(
+            matches!(&err, UniFiError::Controller { endpoint, .. } if endpoint == "stat/device"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_timeout() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let err = api_key_client(&server)
+            .await
+            .get_devices()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "timeout", "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_error_connect() {
+        // Bind and drop a listener to get a port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = UniFiClient::new(
+            format!("http://127.0.0.1:{port}"),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(2),
+            true,
+        )
+        .unwrap();
+
+        let err = client.get_devices().await.unwrap_err();
+        assert_eq!(err.kind(), "connect", "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_login_bad_credentials() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/login"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_string(
+                    r#"{"meta":{"rc":"error","msg":"api.err.Invalid"},"data":[]}"#,
+                ),
+            )
+            .mount(&server)
+            .await;
+
+        let client = UniFiClient::new(
+            server.uri(),
+            None,
+            Some("admin".to_string()),
+            Some("wrong".to_string()),
+            "default".to_string(),
+            Duration::from_secs(2),
+            true,
+        )
+        .unwrap();
+        let err = client.ensure_authenticated().await.unwrap_err();
+        assert!(matches!(err, UniFiError::AuthenticationFailed));
+    }
+
+    fn user_pass_client(server: &wiremock::MockServer) -> UniFiClient {
+        UniFiClient::new(
+            server.uri(),
+            None,
+            Some("admin".to_string()),
+            Some("password".to_string()),
+            "default".to_string(),
+            Duration::from_secs(2),
+            true,
+        )
+        .unwrap()
+    }
+
+    const EMPTY_RESPONSE: &str = r#"{"meta":{"rc":"ok"},"data":[]}"#;
+
+    #[tokio::test]
+    async fn test_detect_unifi_os() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(200).insert_header("Set-Cookie", "TOKEN=abc"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server);
+        client.ensure_authenticated().await.unwrap();
+        assert_eq!(
+            client.controller_flavor().await.unwrap(),
+            ControllerFlavor::UnifiOs
+        );
+        assert!(client.get_devices().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_detect_classic() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"meta":{"rc":"ok","up":true,"server_version":"8.0.24"},"data":[]}"#,
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/login"))
+            .respond_with(ResponseTemplate::new(200).insert_header("Set-Cookie", "unifises=abc"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/s/default/stat/sta"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server);
+        client.ensure_authenticated().await.unwrap();
+        assert_eq!(
+            client.controller_flavor().await.unwrap(),
+            ControllerFlavor::Classic
+        );
+        assert!(client.get_clients().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_detect_by_root_page() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html></html>"))
+            .mount(&server)
+            .await;
+        let client = user_pass_client(&server);
+        assert_eq!(
+            client.controller_flavor().await.unwrap(),
+            ControllerFlavor::UnifiOs
+        );
+
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(302).insert_header("Location", "/manage"))
+            .mount(&server)
+            .await;
+        let client = user_pass_client(&server);
+        assert_eq!(
+            client.controller_flavor().await.unwrap(),
+            ControllerFlavor::Classic
+        );
+    }
+
+    #[tokio::test]
+    async fn test_controller_flavor_override() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        // Would detect UniFi OS, but the override wins without probing
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::Classic);
+        assert_eq!(
+            client.controller_flavor().await.unwrap(),
+            ControllerFlavor::Classic
+        );
+    }
+
+    /// An unsigned UniFi OS style JWT expiring `expires_in` seconds from now.
+    fn test_token(expires_in: i64, csrf_token: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = serde_json::json!({"exp": now + expires_in, "csrfToken": csrf_token});
+        format!(
+            "eyJhbGciOiJIUzI1NiJ9.{}.signature",
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_session_parses_cookies_without_attributes() {
+        let mut session = Session::default();
+        session.update(&headers(&[
+            ("set-cookie", "unifises=abc123; Path=/; Secure; HttpOnly"),
+            ("set-cookie", "csrf_token=xyz; Path=/; Secure"),
+        ]));
+        assert_eq!(session.cookie_header(), "unifises=abc123; csrf_token=xyz");
+        assert_eq!(session.expires_at(), None);
+        assert!(!session.needs_refresh(SystemTime::now()));
+
+        // Replacing and deleting cookies
+        session.update(&headers(&[
+            ("set-cookie", "unifises=def456; Path=/"),
+            ("set-cookie", "csrf_token=; Max-Age=0"),
+        ]));
+        assert_eq!(session.cookie_header(), "unifises=def456");
+    }
+
+    #[test]
+    fn test_session_token_expiry_and_csrf() {
+        let token = test_token(3600, "from-jwt");
+        let mut session = Session::default();
+        session.update(&headers(&[(
+            "set-cookie",
+            &format!("TOKEN={token}; path=/; samesite=strict; secure; httponly"),
+        )]));
+        assert_eq!(session.csrf_token.as_deref(), Some("from-jwt"));
+
+        let expires_in = session
+            .expires_at()
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(expires_in > Duration::from_secs(3500));
+        assert!(!session.needs_refresh(SystemTime::now()));
+        assert!(session.needs_refresh(SystemTime::now() + Duration::from_secs(3590)));
+
+        // Header tokens win over the JWT claim, rotated ones over both
+        session.update(&headers(&[(CSRF_TOKEN, "from-header")]));
+        assert_eq!(session.csrf_token.as_deref(), Some("from-header"));
+        session.update(&headers(&[(UPDATED_CSRF_TOKEN, "rotated")]));
+        assert_eq!(session.csrf_token.as_deref(), Some("rotated"));
+    }
+
+    #[test]
+    fn test_session_cookie_max_age() {
+        let mut session = Session::default();
+        session.update(&headers(&[("set-cookie", "unifises=abc; Max-Age=30")]));
+        // Inside the refresh margin
+        assert!(session.needs_refresh(SystemTime::now()));
+        assert!(Session::default().needs_refresh(SystemTime::now()));
+    }
+
+    /// Mount a UniFi OS login returning `token` and a CSRF token.
+    async fn mount_unifi_os_login(server: &wiremock::MockServer, token: &str, expected: u64) {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header(
+                        "Set-Cookie",
+                        format!("TOKEN={token}; path=/; samesite=strict; secure; httponly"),
+                    )
+                    .insert_header("X-CSRF-Token", "csrf-1"),
+            )
+            .expect(expected)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_unifi_os_session_headers_and_csrf_rotation() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let token = test_token(3600, "csrf-1");
+        mount_unifi_os_login(&server, &token, 1).await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .and(header("cookie", format!("TOKEN={token}").as_str()))
+            .and(header("x-csrf-token", "csrf-1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(EMPTY_RESPONSE)
+                    .insert_header("X-Updated-CSRF-Token", "csrf-2"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/sta"))
+            .and(header("x-csrf-token", "csrf-2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        client.ensure_authenticated().await.unwrap();
+        client.get_devices().await.unwrap();
+        client.get_clients().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_refreshed_before_expiry() {
+        let server = wiremock::MockServer::start().await;
+        // Expires within the refresh margin, so every check logs in again
+        mount_unifi_os_login(&server, &test_token(30, "csrf-1"), 2).await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        client.ensure_authenticated().await.unwrap();
+        client.ensure_authenticated().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_reused_until_expiry() {
+        let server = wiremock::MockServer::start().await;
+        mount_unifi_os_login(&server, &test_token(3600, "csrf-1"), 1).await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        client.ensure_authenticated().await.unwrap();
+        client.ensure_authenticated().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_logout() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let token = test_token(3600, "csrf-1");
+        mount_unifi_os_login(&server, &token, 1).await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/logout"))
+            .and(header("cookie", format!("TOKEN={token}").as_str()))
+            .and(header("x-csrf-token", "csrf-1"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        client.ensure_authenticated().await.unwrap();
+        client.logout().await.unwrap();
+        // Nothing left to log out of
+        client.logout().await.unwrap();
+    }
+
+    fn session_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("unifi-session-{}-{name}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn cached_session(server: &wiremock::MockServer, username: &str, token: &str) -> SessionCache {
+        let mut session = Session::default();
+        session.update(&headers(&[
+            ("set-cookie", &format!("TOKEN={token}; path=/")),
+            (CSRF_TOKEN, "cached-csrf"),
+        ]));
+        SessionCache {
+            controller_url: server.uri(),
+            username: username.to_string(),
+            session,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_session_cache_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = session_file("permissions");
+        let mut session = Session::default();
+        session.update(&headers(&[("set-cookie", "unifises=abc; Path=/")]));
+        let cache = SessionCache {
+            controller_url: "https://unifi:8443".to_string(),
+            username: "admin".to_string(),
+            session: session.clone(),
+        };
+        cache.save(&path).unwrap();
+        // Overwriting keeps the permissions
+        cache.save(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = SessionCache::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.session, session);
+    }
+
+    #[tokio::test]
+    async fn test_cached_session_reused_on_startup() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let token = test_token(3600, "cached-csrf");
+        mount_unifi_os_login(&server, &test_token(3600, "csrf-1"), 0).await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .and(header("cookie", format!("TOKEN={token}").as_str()))
+            .and(header("x-csrf-token", "cached-csrf"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let path = session_file("reuse");
+        cached_session(&server, "admin", &token)
+            .save(&path)
+            .unwrap();
+        let client = user_pass_client(&server)
+            .with_controller_flavor(ControllerFlavor::UnifiOs)
+            .with_session_file(path.clone());
+        client.get_devices().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cached_session_rejected_falls_back_to_login() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let stale = test_token(3600, "cached-csrf");
+        let fresh = test_token(3600, "csrf-1");
+        mount_unifi_os_login(&server, &fresh, 1).await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .and(header("cookie", format!("TOKEN={stale}").as_str()))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .and(header("cookie", format!("TOKEN={fresh}").as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let path = session_file("rejected");
+        cached_session(&server, "admin", &stale)
+            .save(&path)
+            .unwrap();
+        let client = user_pass_client(&server)
+            .with_controller_flavor(ControllerFlavor::UnifiOs)
+            .with_session_file(path.clone());
+        client.get_devices().await.unwrap();
+
+        // The new session replaced the stale one on disk
+        let cache = SessionCache::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cache.session.cookie_header(), format!("TOKEN={fresh}"));
+        assert_eq!(cache.session.csrf_token.as_deref(), Some("csrf-1"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_session_for_other_user_ignored() {
+        let server = wiremock::MockServer::start().await;
+        mount_unifi_os_login(&server, &test_token(3600, "csrf-1"), 1).await;
+
+        let path = session_file("other-user");
+        cached_session(&server, "someone-else", &test_token(3600, "cached-csrf"))
+            .save(&path)
+            .unwrap();
+        let client = user_pass_client(&server)
+            .with_controller_flavor(ControllerFlavor::UnifiOs)
+            .with_session_file(path.clone());
+        client.ensure_authenticated().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_login_backoff_delay() {
+        assert_eq!(LoginBackoff::delay(1), Duration::from_secs(30));
+        assert_eq!(LoginBackoff::delay(2), Duration::from_secs(60));
+        assert_eq!(LoginBackoff::delay(4), Duration::from_secs(240));
+        assert_eq!(LoginBackoff::delay(7), LOGIN_BACKOFF_MAX);
+        assert_eq!(LoginBackoff::delay(100), LOGIN_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn test_login_backoff_window() {
+        let now = Instant::now();
+        let mut backoff = LoginBackoff::default();
+        assert_eq!(backoff.remaining(now), None);
+
+        let timeout = UniFiError::Timeout {
+            url: "https://unifi/api/login".to_string(),
+        };
+        assert_eq!(
+            backoff.record_failure(&timeout, now),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            backoff.remaining(now + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(backoff.remaining(now + Duration::from_secs(30)), None);
+        assert_eq!(
+            backoff.record_failure(&timeout, now),
+            Duration::from_secs(60)
+        );
+
+        // A longer Retry-After wins
+        let rate_limited = UniFiError::RateLimited {
+            url: "https://unifi/api/login".to_string(),
+            retry_after: Some(Duration::from_secs(600)),
+        };
+        assert_eq!(
+            backoff.record_failure(&rate_limited, now),
+            Duration::from_secs(600)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_credentials_not_retried() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        assert!(!client.credentials_rejected());
+        for _ in 0..3 {
+            let err = client.ensure_authenticated().await.unwrap_err();
+            assert!(matches!(err, UniFiError::AuthenticationFailed));
+        }
+        let err = client.get_devices().await.unwrap_err();
+        assert!(matches!(err, UniFiError::AuthenticationFailed));
+        assert!(client.credentials_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_transient_login_failure_backs_off() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = user_pass_client(&server).with_controller_flavor(ControllerFlavor::UnifiOs);
+        let err = client.ensure_authenticated().await.unwrap_err();
+        assert_eq!(err.kind(), "server_error");
+
+        // Inside the backoff window no login is attempted
+        let err = client.ensure_authenticated().await.unwrap_err();
+        assert_eq!(err.kind(), "login_backoff");
+        assert!(!client.credentials_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_retried() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(EMPTY_RESPONSE))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server)
+            .await
+            .with_retry_policy(RetryPolicy::new(2));
+        assert!(client.get_devices().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_and_posts_not_retried() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/stat/report/hourly.ap"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server)
+            .await
+            .with_retry_policy(RetryPolicy::new(3));
+        assert_eq!(client.get_devices().await.unwrap_err().kind(), "not_found");
+        let err = client
+            .get_report(ReportInterval::Hourly, "ap", 0, 1, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "server_error");
+    }
+
+    #[tokio::test]
+    async fn test_long_retry_after_not_waited_for() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server)
+            .await
+            .with_retry_policy(RetryPolicy::new(3));
+        let err = client.get_devices().await.unwrap_err();
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(3600)));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_stops_requests() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server)
+            .await
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(60)));
+        for _ in 0..2 {
+            let err = client.get_devices().await.unwrap_err();
+            assert_eq!(err.kind(), "server_error");
+        }
+        let err = client.get_devices().await.unwrap_err();
+        assert_eq!(err.kind(), "circuit_open");
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_timeout_overrides_client_timeout() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let slow = ResponseTemplate::new(200)
+            .set_body_string(EMPTY_RESPONSE)
+            .set_delay(Duration::from_millis(500));
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/device"))
+            .respond_with(slow.clone())
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/sta"))
+            .respond_with(slow)
+            .mount(&server)
+            .await;
+
+        let client = api_key_client(&server)
+            .await
+            .with_endpoint_timeouts(HashMap::from([(
+                "stat/device".to_string(),
+                Duration::from_millis(100),
+            )]));
+        assert_eq!(client.get_devices().await.unwrap_err().kind(), "timeout");
+        assert!(client.get_clients().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_bounded() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Counts the requests the controller is serving at once
+        #[derive(Default)]
+        struct InFlight {
+            current: AtomicUsize,
+            peak: AtomicUsize,
+        }
+
+        let in_flight = std::sync::Arc::new(InFlight::default());
+        let app = axum::Router::new()
+            .fallback(
+                |axum::extract::State(in_flight): axum::extract::State<
+                    std::sync::Arc<InFlight>,
+                >| async move {
+                    let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+                    in_flight.peak.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    in_flight.current.fetch_sub(1, Ordering::SeqCst);
+                    EMPTY_RESPONSE
+                },
+            )
+            .with_state(in_flight.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = UniFiClient::new(
+            url,
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(5),
+            true,
+        )
+        .unwrap()
+        .with_max_concurrent_requests(2);
+        let results = futures_util::future::join_all((0..6).map(|_| client.get_devices())).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(in_flight.peak.load(Ordering::SeqCst), 2);
+    }
+
     #[tokio::test]
     async fn test_ensure_authenticated_with_api_key() {
         let client = UniFiClient::new(
@@ -1,10 +1,21 @@
+use futures_util::future::join_all;
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::unifi::{Client, Device, Result, SysStats, UniFiClient, UniFiError, Uplink, decode};
+
+/// Items requested per page; the largest page the Integration API serves.
+const PAGE_LIMIT: usize = 200;
 
 // Integration API response wrapper
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 pub struct IntegrationResponse<T> {
     pub offset: u32,
+    /// Page size the controller applied
     pub limit: u32,
     pub count: u32,
     #[serde(rename = "totalCount")]
@@ -12,6 +23,15 @@ pub struct IntegrationResponse<T> {
     pub data: Vec<T>,
 }
 
+impl<T> IntegrationResponse<T> {
+    /// Offset of the next page, or `None` if this was the last one: a short
+    /// page ends the list even if items were removed while paging.
+    fn next_offset(&self) -> Option<u32> {
+        let next = self.offset + self.count;
+        (self.count > 0 && self.count >= self.limit && next < self.total_count).then_some(next)
+    }
+}
+
 // Site structure for Integration API
 #[derive(Debug, Deserialize, Clone)]
 pub struct IntegrationSite {
@@ -21,8 +41,72 @@ pub struct IntegrationSite {
     pub name: String,
 }
 
-// Note: IntegrationDevice and IntegrationClient structs were removed
-// as we now use the regular API endpoints instead of Integration API
+/// A device as listed by `sites/{siteId}/devices`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationDevice {
+    pub id: String,
+    pub name: Option<String>,
+    pub model: Option<String>,
+    pub mac_address: String,
+    /// `ONLINE`, `OFFLINE`, `PENDING_ADOPTION`, `UPDATING`, ...
+    pub state: Option<String>,
+    pub firmware_version: Option<String>,
+    pub firmware_updatable: Option<bool>,
+    /// `accessPoint`, `switching`, ...
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// The parts of `sites/{siteId}/devices/{deviceId}` the list lacks.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationDeviceDetails {
+    pub firmware_version: Option<String>,
+    pub firmware_updatable: Option<bool>,
+    pub uplink: Option<IntegrationUplink>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationUplink {
+    pub device_id: Option<String>,
+}
+
+/// `sites/{siteId}/devices/{deviceId}/statistics/latest`
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationDeviceStatistics {
+    pub uptime_sec: Option<i64>,
+    pub load_average_1_min: Option<f64>,
+    pub load_average_5_min: Option<f64>,
+    pub load_average_15_min: Option<f64>,
+}
+
+/// A client as listed by `sites/{siteId}/clients`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationClient {
+    pub id: String,
+    pub name: Option<String>,
+    /// `WIRED`, `WIRELESS` or `VPN`
+    #[serde(rename = "type")]
+    pub client_type: Option<String>,
+    /// Missing for VPN clients
+    pub mac_address: Option<String>,
+    pub ip_address: Option<String>,
+    /// RFC 3339 timestamp
+    pub connected_at: Option<String>,
+    pub uplink_device_id: Option<String>,
+    pub access: Option<IntegrationAccess>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IntegrationAccess {
+    /// `DEFAULT` or `GUEST`
+    #[serde(rename = "type")]
+    pub access_type: Option<String>,
+}
 
 impl IntegrationSite {
     pub fn to_site(&self) -> crate::unifi::Site {
@@ -36,9 +120,277 @@ impl IntegrationSite {
     }
 }
 
+impl IntegrationDevice {
+    /// Classic API device type (`uap`, `usw`) from the device features.
+    fn device_type(&self) -> &'static str {
+        if self.features.iter().any(|f| f == "accessPoint") {
+            "uap"
+        } else if self.features.iter().any(|f| f == "switching") {
+            "usw"
+        } else {
+            "unknown"
+        }
+    }
+
+    /// Classic API state code of the device state.
+    fn state_code(&self) -> i32 {
+        match self.state.as_deref() {
+            Some("ONLINE") => 1,
+            Some("PENDING_ADOPTION") => 2,
+            Some("UPDATING") => 4,
+            Some("GETTING_READY") => 5,
+            Some("CONNECTION_INTERRUPTED") => 6,
+            Some("ADOPTING") => 7,
+            Some("ISOLATED") => 11,
+            _ => 0,
+        }
+    }
+
+    /// Map into the classic API device. `device_macs` maps device ids to
+    /// MAC addresses, to name the uplink device.
+    pub fn to_device(
+        &self,
+        details: Option<&IntegrationDeviceDetails>,
+        statistics: Option<&IntegrationDeviceStatistics>,
+        device_macs: &HashMap<String, String>,
+    ) -> Device {
+        let uplink = details
+            .and_then(|d| d.uplink.as_ref())
+            .and_then(|u| u.device_id.as_ref())
+            .map(|id| Uplink {
+                uplink_mac: device_macs.get(id).cloned(),
+                ..Default::default()
+            });
+
+        Device {
+            _id: self.id.clone(),
+            name: self.name.clone(),
+            mac: self.mac_address.to_lowercase(),
+            device_type: self.device_type().to_string(),
+            model: self.model.clone(),
+            version: details
+                .and_then(|d| d.firmware_version.clone())
+                .or_else(|| self.firmware_version.clone()),
+            adopted: self.state.as_deref() != Some("PENDING_ADOPTION"),
+            state: self.state_code(),
+            uptime: statistics.and_then(|s| s.uptime_sec),
+            sys_stats: statistics.map(|s| SysStats {
+                loadavg_1: s.load_average_1_min,
+                loadavg_5: s.load_average_5_min,
+                loadavg_15: s.load_average_15_min,
+                mem_total: None,
+                mem_used: None,
+            }),
+            upgradable: details
+                .and_then(|d| d.firmware_updatable)
+                .or(self.firmware_updatable)
+                .unwrap_or(false),
+            uplink,
+            ..Default::default()
+        }
+    }
+}
+
+impl IntegrationClient {
+    /// Map into the classic API client. `device_macs` maps device ids to
+    /// MAC addresses, to name the AP or switch the client connects to;
+    /// `now` is in seconds since the Unix epoch.
+    pub fn to_client(&self, device_macs: &HashMap<String, String>, now: i64) -> Client {
+        let is_wired = self.client_type.as_deref() == Some("WIRED");
+        let uplink_mac = self
+            .uplink_device_id
+            .as_ref()
+            .and_then(|id| device_macs.get(id).cloned());
+        let (ap_mac, sw_mac) = match self.client_type.as_deref() {
+            Some("WIRELESS") => (uplink_mac, None),
+            Some("WIRED") => (None, uplink_mac),
+            _ => (None, None),
+        };
+
+        Client {
+            _id: self.id.clone(),
+            mac: self
+                .mac_address
+                .as_deref()
+                .unwrap_or_default()
+                .to_lowercase(),
+            ip: self.ip_address.clone(),
+            name: self.name.clone(),
+            ap_mac,
+            sw_mac,
+            uptime: self
+                .connected_at
+                .as_deref()
+                .and_then(parse_timestamp)
+                .map(|connected_at| (now - connected_at).max(0)),
+            is_wired,
+            is_guest: self.access.as_ref().and_then(|a| a.access_type.as_deref()) == Some("GUEST"),
+            ..Default::default()
+        }
+    }
+}
+
+/// Seconds since the Unix epoch of an RFC 3339 timestamp such as
+/// `2024-05-01T12:30:00Z` or `2024-05-01T14:30:00.250+02:00`.
+fn parse_timestamp(value: &str) -> Option<i64> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .ok()
+        .map(OffsetDateTime::unix_timestamp)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Integration API requests. They are named after the classic API endpoint
+/// with the same data (`stat/device`, `stat/sta`, `sites`), so error metrics
+/// and `ENDPOINT_TIMEOUTS` apply to either API.
+impl UniFiClient {
+    fn integration_url(&self, path: &str) -> String {
+        format!("{}/proxy/network/integration/v1/{}", self.base_url, path)
+    }
+
+    async fn get_integration<T>(&self, endpoint: &str, path: &str) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let url = self.integration_url(path);
+        let body = self
+            .send(endpoint, reqwest::Method::GET, &url, None)
+            .await?;
+        decode(endpoint, &body)
+    }
+
+    /// Fetch every page of a paginated Integration API list.
+    async fn get_integration_paged<T>(&self, endpoint: &str, path: &str) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut items = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page_path = format!("{path}?offset={offset}&limit={PAGE_LIMIT}");
+            let page: IntegrationResponse<T> = self.get_integration(endpoint, &page_path).await?;
+            let next_offset = page.next_offset();
+            items.extend(page.data);
+            match next_offset {
+                Some(next) => offset = next,
+                None => return Ok(items),
+            }
+        }
+    }
+
+    pub(crate) async fn get_integration_sites(&self) -> Result<Vec<IntegrationSite>> {
+        self.get_integration_paged("sites", "sites").await
+    }
+
+    /// Integration API id of the configured site, which may be given by
+    /// name (`default`) or id.
+    async fn integration_site_id(&self) -> Result<&str> {
+        self.integration_site_id
+            .get_or_try_init(|| async {
+                self.get_integration_sites()
+                    .await?
+                    .into_iter()
+                    .find(|site| site.internal_reference == self.site || site.id == self.site)
+                    .map(|site| site.id)
+                    .ok_or_else(|| {
+                        UniFiError::InvalidConfig(format!(
+                            "Site '{}' not found on the controller",
+                            self.site
+                        ))
+                    })
+            })
+            .await
+            .map(String::as_str)
+    }
+
+    /// The device list, also remembering the MAC address of each device id.
+    async fn get_integration_device_list(&self) -> Result<Vec<IntegrationDevice>> {
+        let site_id = self.integration_site_id().await?;
+        let devices: Vec<IntegrationDevice> = self
+            .get_integration_paged("stat/device", &format!("sites/{site_id}/devices"))
+            .await?;
+        *self.integration_device_macs.lock().unwrap() = device_macs(&devices);
+        Ok(devices)
+    }
+
+    /// Devices with their details and latest statistics. A device whose
+    /// details or statistics fail to load keeps the data from the list, and
+    /// the failure is counted instead of failing every device.
+    pub(crate) async fn get_integration_devices(&self) -> Result<Vec<Device>> {
+        let site_id = self.integration_site_id().await?;
+        let devices = self.get_integration_device_list().await?;
+        let device_macs = device_macs(&devices);
+
+        Ok(join_all(devices.iter().map(|device| async {
+            let path = format!("sites/{site_id}/devices/{}", device.id);
+            let statistics_path = format!("{path}/statistics/latest");
+            let (details, statistics) = tokio::join!(
+                self.get_integration::<IntegrationDeviceDetails>("stat/device", &path),
+                self.get_integration::<IntegrationDeviceStatistics>(
+                    "stat/device",
+                    &statistics_path
+                ),
+            );
+            let details = details
+                .map_err(|e| self.record_partial_error("stat/device", e))
+                .ok();
+            let statistics = match statistics {
+                // Devices that never reported have no statistics
+                Err(UniFiError::NotFound { .. }) => None,
+                statistics => statistics
+                    .map_err(|e| self.record_partial_error("stat/device", e))
+                    .ok(),
+            };
+            device.to_device(details.as_ref(), statistics.as_ref(), &device_macs)
+        }))
+        .await)
+    }
+
+    /// Clients, with the devices they connect to named from the last device
+    /// list. The list is only fetched again when a client connects to a
+    /// device missing from it.
+    pub(crate) async fn get_integration_clients(&self) -> Result<Vec<Client>> {
+        let site_id = self.integration_site_id().await?;
+        let clients: Vec<IntegrationClient> = self
+            .get_integration_paged("stat/sta", &format!("sites/{site_id}/clients"))
+            .await?;
+        let unknown_device = {
+            let device_macs = self.integration_device_macs.lock().unwrap();
+            clients
+                .iter()
+                .filter_map(|client| client.uplink_device_id.as_ref())
+                .any(|id| !device_macs.contains_key(id))
+        };
+        if unknown_device {
+            self.get_integration_device_list().await?;
+        }
+        let device_macs = self.integration_device_macs.lock().unwrap().clone();
+        let now = unix_now();
+        Ok(clients
+            .iter()
+            .map(|client| client.to_client(&device_macs, now))
+            .collect())
+    }
+}
+
+fn device_macs(devices: &[IntegrationDevice]) -> HashMap<String, String> {
+    devices
+        .iter()
+        .map(|device| (device.id.clone(), device.mac_address.to_lowercase()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[test]
     fn test_integration_response_deserialize() {
@@ -57,6 +409,22 @@ mod tests {
         assert_eq!(response.data.len(), 0);
     }
 
+    #[test]
+    fn test_integration_response_next_offset() {
+        let page = |offset, count, total_count| IntegrationResponse::<IntegrationSite> {
+            offset,
+            limit: 200,
+            count,
+            total_count,
+            data: vec![],
+        };
+        assert_eq!(page(0, 200, 450).next_offset(), Some(200));
+        assert_eq!(page(400, 50, 450).next_offset(), None);
+        // A short page before the total was reached must not loop forever
+        assert_eq!(page(200, 0, 450).next_offset(), None);
+        assert_eq!(page(200, 150, 450).next_offset(), None);
+    }
+
     #[test]
     fn test_integration_site_deserialize() {
         let json = r#"{
@@ -85,4 +453,496 @@ mod tests {
         assert_eq!(site.attr_hidden_id, None);
         assert_eq!(site.attr_no_delete, None);
     }
+
+    fn test_device(id: &str, mac: &str, state: &str, features: &[&str]) -> IntegrationDevice {
+        IntegrationDevice {
+            id: id.to_string(),
+            name: Some(format!("device-{id}")),
+            model: Some("U6 Pro".to_string()),
+            mac_address: mac.to_string(),
+            state: Some(state.to_string()),
+            firmware_version: Some("6.6.55".to_string()),
+            firmware_updatable: Some(false),
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_integration_device_to_device() {
+        let macs = HashMap::from([("switch".to_string(), "f4:e2:c6:00:00:01".to_string())]);
+        let details = IntegrationDeviceDetails {
+            firmware_version: Some("6.7.10".to_string()),
+            firmware_updatable: Some(true),
+            uplink: Some(IntegrationUplink {
+                device_id: Some("switch".to_string()),
+            }),
+        };
+        let statistics = IntegrationDeviceStatistics {
+            uptime_sec: Some(86_400),
+            load_average_1_min: Some(0.5),
+            load_average_5_min: Some(0.25),
+            load_average_15_min: None,
+        };
+
+        let device = test_device("ap", "F4:E2:C6:00:00:02", "ONLINE", &["accessPoint"]).to_device(
+            Some(&details),
+            Some(&statistics),
+            &macs,
+        );
+        assert_eq!(device._id, "ap");
+        assert_eq!(device.mac, "f4:e2:c6:00:00:02");
+        assert_eq!(device.device_type, "uap");
+        assert_eq!(device.version.as_deref(), Some("6.7.10"));
+        assert!(device.adopted);
+        assert!(device.upgradable);
+        assert_eq!(device.state, 1);
+        assert_eq!(device.uptime, Some(86_400));
+        assert_eq!(device.sys_stats.unwrap().loadavg_5, Some(0.25));
+        assert_eq!(
+            device.uplink.unwrap().uplink_mac.as_deref(),
+            Some("f4:e2:c6:00:00:01")
+        );
+    }
+
+    #[test]
+    fn test_integration_device_without_details() {
+        let device = test_device(
+            "sw",
+            "f4:e2:c6:00:00:01",
+            "PENDING_ADOPTION",
+            &["switching"],
+        )
+        .to_device(None, None, &HashMap::new());
+        assert_eq!(device.device_type, "usw");
+        assert_eq!(device.version.as_deref(), Some("6.6.55"));
+        assert!(!device.adopted);
+        assert_eq!(device.state, 2);
+        assert!(device.uptime.is_none());
+        assert!(device.sys_stats.is_none());
+        assert!(device.uplink.is_none());
+    }
+
+    #[test]
+    fn test_integration_client_to_client() {
+        let macs = HashMap::from([("ap".to_string(), "f4:e2:c6:00:00:02".to_string())]);
+        let json = r#"{
+            "id": "c1",
+            "name": "Phone",
+            "type": "WIRELESS",
+            "macAddress": "AA:BB:CC:DD:EE:FF",
+            "ipAddress": "192.168.1.50",
+            "connectedAt": "2024-05-01T12:00:00Z",
+            "uplinkDeviceId": "ap",
+            "access": {"type": "GUEST"}
+        }"#;
+        let client: IntegrationClient = serde_json::from_str(json).unwrap();
+        let now = parse_timestamp("2024-05-01T13:00:00Z").unwrap();
+
+        let client = client.to_client(&macs, now);
+        assert_eq!(client.mac, "aa:bb:cc:dd:ee:ff");
+        assert_eq!(client.ap_mac.as_deref(), Some("f4:e2:c6:00:00:02"));
+        assert!(client.sw_mac.is_none());
+        assert!(!client.is_wired);
+        assert!(client.is_guest);
+        assert_eq!(client.uptime, Some(3600));
+    }
+
+    #[test]
+    fn test_integration_wired_client() {
+        let macs = HashMap::from([("sw".to_string(), "f4:e2:c6:00:00:01".to_string())]);
+        let json = r#"{"id": "c2", "type": "WIRED", "macAddress": "11:22:33:44:55:66", "uplinkDeviceId": "sw"}"#;
+        let client: IntegrationClient = serde_json::from_str(json).unwrap();
+
+        let client = client.to_client(&macs, 0);
+        assert!(client.is_wired);
+        assert!(!client.is_guest);
+        assert_eq!(client.sw_mac.as_deref(), Some("f4:e2:c6:00:00:01"));
+        assert!(client.ap_mac.is_none());
+        assert!(client.uptime.is_none());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2024-02-29T12:30:15Z"), Some(1_709_209_815));
+        assert_eq!(
+            parse_timestamp("2024-02-29T14:30:15.250+02:00"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(
+            parse_timestamp("2024-02-29T07:00:15-05:30"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2024-02-29"), None);
+    }
+
+    const SITE_ID: &str = "88f7af54-98f8-306a-a1c7-c9349722b1f6";
+
+    fn page(offset: usize, total_count: usize, data: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({
+            "offset": offset,
+            "limit": PAGE_LIMIT,
+            "count": data.len(),
+            "totalCount": total_count,
+            "data": data,
+        })
+    }
+
+    async fn mount_sites(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/integration/v1/sites"))
+            .and(header("X-API-KEY", "test-api-key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                1,
+                vec![serde_json::json!({
+                    "id": SITE_ID,
+                    "internalReference": "default",
+                    "name": "Default"
+                })],
+            )))
+            .mount(server)
+            .await;
+    }
+
+    fn integration_client(server: &MockServer) -> UniFiClient {
+        UniFiClient::new(
+            server.uri(),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "default".to_string(),
+            Duration::from_secs(5),
+            true,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_devices_paginates() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let devices_path = format!("/proxy/network/integration/v1/sites/{SITE_ID}/devices");
+        let devices: Vec<_> = (0..250)
+            .map(|i| {
+                serde_json::json!({
+                    "id": format!("d{i}"),
+                    "name": format!("AP {i}"),
+                    "macAddress": format!("f4:e2:c6:00:{:02x}:{:02x}", i / 256, i % 256),
+                    "state": "ONLINE",
+                    "features": ["accessPoint"]
+                })
+            })
+            .collect();
+        for (offset, chunk) in [(0, &devices[..200]), (200, &devices[200..])] {
+            Mock::given(method("GET"))
+                .and(path(devices_path.clone()))
+                .and(query_param("offset", offset.to_string()))
+                .and(query_param("limit", PAGE_LIMIT.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                    offset,
+                    250,
+                    chunk.to_vec(),
+                )))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        // Details and statistics of every device
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path_regex(format!(
+                "^{devices_path}/d[0-9]+$"
+            )))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(r#"{"firmwareVersion": "6.7.10"}"#),
+            )
+            .expect(250)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path_regex(format!(
+                "^{devices_path}/d[0-9]+/statistics/latest$"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"uptimeSec": 60}"#))
+            .expect(250)
+            .mount(&server)
+            .await;
+
+        let devices = integration_client(&server)
+            .get_integration_devices()
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 250);
+        assert_eq!(devices[249]._id, "d249");
+        assert!(devices.iter().all(|d| d.uptime == Some(60)));
+        assert!(
+            devices
+                .iter()
+                .all(|d| d.version.as_deref() == Some("6.7.10"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_devices_without_statistics() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let devices_path = format!("/proxy/network/integration/v1/sites/{SITE_ID}/devices");
+        Mock::given(method("GET"))
+            .and(path(devices_path.clone()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                1,
+                vec![serde_json::json!({
+                    "id": "d1",
+                    "macAddress": "f4:e2:c6:00:00:01",
+                    "state": "OFFLINE",
+                    "features": ["switching"]
+                })],
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{devices_path}/d1")))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{devices_path}/d1/statistics/latest")))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let devices = integration_client(&server)
+            .get_integration_devices()
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].state, 0);
+        assert!(devices[0].uptime.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_devices_with_failing_details() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let devices_path = format!("/proxy/network/integration/v1/sites/{SITE_ID}/devices");
+        Mock::given(method("GET"))
+            .and(path(devices_path.clone()))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(page(
+                    0,
+                    2,
+                    ["d1", "d2"]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, id)| {
+                            serde_json::json!({
+                                "id": id,
+                                "macAddress": format!("f4:e2:c6:00:00:0{}", i + 1),
+                                "state": "ONLINE",
+                                "firmwareVersion": "6.6.55",
+                                "features": ["accessPoint"]
+                            })
+                        })
+                        .collect(),
+                )),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{devices_path}/d1")))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{devices_path}/d2")))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(r#"{"firmwareVersion": "6.7.10"}"#),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path_regex(format!(
+                "^{devices_path}/d[0-9]+/statistics/latest$"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"uptimeSec": 60}"#))
+            .mount(&server)
+            .await;
+
+        let client =
+            integration_client(&server).with_retry_policy(crate::retry::RetryPolicy::new(0));
+        let devices = client.get_integration_devices().await.unwrap();
+        // The device without details keeps the data from the list
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].version.as_deref(), Some("6.6.55"));
+        assert_eq!(devices[0].uptime, Some(60));
+        assert_eq!(devices[1].version.as_deref(), Some("6.7.10"));
+
+        let errors = client.take_partial_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "stat/device");
+        assert_eq!(errors[0].1.status(), Some(500));
+        assert!(client.take_partial_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_clients() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let site_path = format!("/proxy/network/integration/v1/sites/{SITE_ID}");
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/devices")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                1,
+                vec![serde_json::json!({
+                    "id": "ap",
+                    "macAddress": "F4:E2:C6:00:00:02",
+                    "state": "ONLINE",
+                    "features": ["accessPoint"]
+                })],
+            )))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/clients")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                2,
+                vec![
+                    serde_json::json!({
+                        "id": "c1",
+                        "type": "WIRELESS",
+                        "macAddress": "aa:bb:cc:dd:ee:ff",
+                        "uplinkDeviceId": "ap"
+                    }),
+                    serde_json::json!({"id": "c2", "type": "VPN"}),
+                ],
+            )))
+            .mount(&server)
+            .await;
+
+        let clients = integration_client(&server)
+            .get_integration_clients()
+            .await
+            .unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].ap_mac.as_deref(), Some("f4:e2:c6:00:00:02"));
+        assert_eq!(clients[1].mac, "");
+    }
+
+    #[tokio::test]
+    async fn test_get_clients_reuses_device_list() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let site_path = format!("/proxy/network/integration/v1/sites/{SITE_ID}");
+        // Listed for the first clients, which name an unknown device, and
+        // for the devices; the next clients reuse it
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/devices")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                1,
+                vec![serde_json::json!({
+                    "id": "ap",
+                    "macAddress": "F4:E2:C6:00:00:02",
+                    "state": "ONLINE",
+                    "features": ["accessPoint"]
+                })],
+            )))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/devices/ap")))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/devices/ap/statistics/latest")))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("{site_path}/clients")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                0,
+                1,
+                vec![serde_json::json!({
+                    "id": "c1",
+                    "type": "WIRELESS",
+                    "macAddress": "aa:bb:cc:dd:ee:ff",
+                    "uplinkDeviceId": "ap"
+                })],
+            )))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = integration_client(&server);
+        let clients = client.get_integration_clients().await.unwrap();
+        assert_eq!(clients[0].ap_mac.as_deref(), Some("f4:e2:c6:00:00:02"));
+        client.get_integration_devices().await.unwrap();
+        let clients = client.get_integration_clients().await.unwrap();
+        assert_eq!(clients[0].ap_mac.as_deref(), Some("f4:e2:c6:00:00:02"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_site() {
+        let server = MockServer::start().await;
+        mount_sites(&server).await;
+
+        let client = UniFiClient::new(
+            server.uri(),
+            Some("test-api-key".to_string()),
+            None,
+            None,
+            "branch".to_string(),
+            Duration::from_secs(5),
+            true,
+        )
+        .unwrap();
+        let err = client.get_integration_devices().await.unwrap_err();
+        assert_eq!(err.kind(), "config");
+    }
+
+    #[tokio::test]
+    async fn test_get_sites_paginates() {
+        let server = MockServer::start().await;
+        let sites: Vec<_> = (0..201)
+            .map(|i| {
+                serde_json::json!({
+                    "id": format!("site-{i}"),
+                    "internalReference": format!("s{i}"),
+                    "name": format!("Site {i}")
+                })
+            })
+            .collect();
+        for (offset, chunk) in [(0, &sites[..200]), (200, &sites[200..])] {
+            Mock::given(method("GET"))
+                .and(path("/proxy/network/integration/v1/sites"))
+                .and(query_param("offset", offset.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page(
+                    offset,
+                    201,
+                    chunk.to_vec(),
+                )))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let sites = integration_client(&server)
+            .get_integration_sites()
+            .await
+            .unwrap();
+        assert_eq!(sites.len(), 201);
+        assert_eq!(sites[200].to_site().name, "s200");
+    }
 }
@@ -0,0 +1,409 @@
+//! End-to-end tests: the exporter's poll loop against a simulated controller,
+//! observed through `/metrics`.
+
+mod simulator;
+
+use clap::Parser;
+use simulator::{Faults, Flavor, SimSite, Simulator, client, device};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use unifi_network_exporter::backend::ReplayBackend;
+use unifi_network_exporter::config::Config;
+use unifi_network_exporter::exporter;
+use unifi_network_exporter::metrics::Metrics;
+use unifi_network_exporter::topology::Topology;
+
+const AP_MAC: &str = "f4:e2:c6:00:00:01";
+
+fn default_site() -> SimSite {
+    SimSite::new("default", "Default")
+        .with_device(device("d1", AP_MAC, "uap", "Office AP"))
+        .with_device(device("d2", "f4:e2:c6:00:00:02", "usw", "Core Switch"))
+        .with_client(client("c1", "aa:bb:cc:00:00:01", "laptop", Some(AP_MAC)))
+        .with_client(client("c2", "aa:bb:cc:00:00:02", "nas", None))
+}
+
+fn branch_site() -> SimSite {
+    SimSite::new("branch", "Branch Office").with_device(device(
+        "d3",
+        "f4:e2:c6:00:00:03",
+        "uap",
+        "Branch AP",
+    ))
+}
+
+/// The exporter as `main` runs it: configuration, client, backend, poll loop
+/// and metrics server.
+struct Exporter {
+    url: String,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// The configuration for polling `controller` with the extra `args`.
+fn config(controller: &Simulator, args: &[&str]) -> Config {
+    let url = controller.url();
+    let mut argv = vec![
+        "unifi-network-exporter",
+        "--controller-url",
+        &url,
+        "--http-retries",
+        "0",
+        "--circuit-breaker-threshold",
+        "0",
+    ];
+    argv.extend_from_slice(args);
+    let config = Config::try_parse_from(argv).unwrap();
+    config.validate().unwrap();
+    config
+}
+
+impl Exporter {
+    async fn start(controller: &Simulator, args: &[&str]) -> Self {
+        let config = config(controller, args);
+        let client = exporter::build_client(&config).unwrap();
+        let backend = exporter::build_backend(&config, client);
+        let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+        let topology = Arc::new(RwLock::new(Topology::default()));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = exporter::router(metrics.clone(), topology.clone());
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let poll = tokio::spawn(exporter::poll_loop(
+            backend,
+            metrics,
+            topology,
+            Duration::from_millis(100),
+        ));
+
+        Self {
+            url: format!("http://{addr}"),
+            tasks: vec![server, poll],
+        }
+    }
+
+    async fn scrape(&self) -> String {
+        reqwest::get(format!("{}/metrics", self.url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// Scrape until the exposition contains all of `lines`, failing after a
+    /// few seconds.
+    async fn scrape_until(&self, lines: &[&str]) -> String {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let output = self.scrape().await;
+            if lines.iter().all(|line| output.contains(line)) {
+                return output;
+            }
+            if tokio::time::Instant::now() > deadline {
+                panic!("metrics never contained {lines:?}:\n{output}");
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+#[tokio::test]
+async fn test_classic_controller_login() {
+    let controller = Simulator::builder(Flavor::Classic)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &["--username", "admin", "--password", "secret"],
+    )
+    .await;
+
+    exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#,
+            r#"unifi_device_state{id="d2",mac="f4:e2:c6:00:00:02",name="Core Switch"} 1"#,
+            r#"unifi_clients_total{is_guest="false",network="all",type="wireless"} 1"#,
+            r#"unifi_clients_total{is_guest="false",network="all",type="wired"} 1"#,
+            "unifi_sites_total 1",
+            "unifi_auth_failure 0",
+        ])
+        .await;
+
+    // Later polls reuse the session
+    let requests = controller.requests();
+    while controller.requests() < requests + 6 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(controller.logins(), 1);
+}
+
+#[tokio::test]
+async fn test_unifi_os_site_selection() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .site(branch_site())
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &[
+            "--username",
+            "admin",
+            "--password",
+            "secret",
+            "--site",
+            "branch",
+        ],
+    )
+    .await;
+
+    let output = exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d3",mac="f4:e2:c6:00:00:03",name="Branch AP"} 1"#,
+            "unifi_sites_total 2",
+        ])
+        .await;
+    assert!(!output.contains("Office AP"), "{output}");
+    assert_eq!(controller.logins(), 1);
+}
+
+#[tokio::test]
+async fn test_api_key() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .api_key("test-api-key")
+        .site(default_site())
+        .site(branch_site())
+        .start()
+        .await;
+    let exporter = Exporter::start(&controller, &["--api-key", "test-api-key"]).await;
+
+    exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#,
+            // Sites come from the Integration API with an API key
+            "unifi_sites_total 2",
+        ])
+        .await;
+    assert_eq!(controller.logins(), 0);
+}
+
+#[tokio::test]
+async fn test_rejected_credentials() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .start()
+        .await;
+    let exporter =
+        Exporter::start(&controller, &["--username", "admin", "--password", "wrong"]).await;
+
+    let output = exporter
+        .scrape_until(&[
+            "unifi_auth_failure 1",
+            r#"unifi_exporter_errors_total{endpoint="login",kind="authentication"}"#,
+        ])
+        .await;
+    assert!(!output.contains("unifi_device_state{"), "{output}");
+}
+
+#[tokio::test]
+async fn test_session_expiry_renews_login() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .faults(Faults {
+            unauthorized_after: Some(4),
+            ..Default::default()
+        })
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &["--username", "admin", "--password", "secret"],
+    )
+    .await;
+
+    exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#,
+        ])
+        .await;
+    while controller.logins() < 3 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // The 401s were answered by logging in again, not reported as errors
+    let output = exporter.scrape().await;
+    assert!(!output.contains(r#"kind="unauthorized""#), "{output}");
+    assert!(output.contains("unifi_auth_failure 0"), "{output}");
+}
+
+#[tokio::test]
+async fn test_slow_controller_times_out_and_recovers() {
+    let controller = Simulator::builder(Flavor::Classic)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .faults(Faults {
+            delay: Some(Duration::from_secs(3)),
+            ..Default::default()
+        })
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &[
+            "--username",
+            "admin",
+            "--password",
+            "secret",
+            "--http-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    let output = exporter
+        .scrape_until(&[r#"unifi_exporter_errors_total{endpoint="stat/device",kind="timeout"}"#])
+        .await;
+    assert!(!output.contains("unifi_device_state{"), "{output}");
+
+    controller.set_faults(Faults::default());
+    exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#,
+        ])
+        .await;
+}
+
+#[tokio::test]
+async fn test_malformed_json() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .credentials("admin", "secret")
+        .site(default_site())
+        .faults(Faults {
+            malformed_json: ["stat/sta".to_string()].into(),
+            ..Default::default()
+        })
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &["--username", "admin", "--password", "secret"],
+    )
+    .await;
+
+    let output = exporter
+        .scrape_until(&[r#"unifi_exporter_errors_total{endpoint="stat/sta",kind="decode"}"#])
+        .await;
+    assert!(!output.contains(r#"endpoint="stat/device""#), "{output}");
+}
+
+#[tokio::test]
+async fn test_bad_record_is_skipped() {
+    let mut bad_device = device("d4", "f4:e2:c6:00:00:04", "uap", "Attic AP");
+    bad_device["sys_stats"] = serde_json::json!({ "loadavg_1": 0.5 });
+    let controller = Simulator::builder(Flavor::Classic)
+        .credentials("admin", "secret")
+        .site(default_site().with_device(bad_device))
+        .start()
+        .await;
+    let exporter = Exporter::start(
+        &controller,
+        &["--username", "admin", "--password", "secret"],
+    )
+    .await;
+
+    let output = exporter
+        .scrape_until(&[
+            r#"unifi_device_state{id="d1",mac="f4:e2:c6:00:00:01",name="Office AP"} 1"#,
+            r#"unifi_exporter_parse_errors_total{endpoint="stat/device",field="sys_stats.loadavg_1"}"#,
+        ])
+        .await;
+    assert!(!output.contains("Attic AP"), "{output}");
+    assert!(!output.contains("unifi_exporter_errors_total{"), "{output}");
+}
+
+/// Poll `controller` once while recording, then poll the capture, and return
+/// the metrics of the replay.
+async fn record_and_replay(controller: &Simulator, name: &str, args: &[&str]) -> String {
+    let dir = std::env::temp_dir().join(format!("unifi-e2e-record-{name}-{}", std::process::id()));
+    let record_dir = dir.to_string_lossy().into_owned();
+    let mut args = args.to_vec();
+    args.extend(["--record-dir", &record_dir]);
+    let config = config(controller, &args);
+    let backend = exporter::build_backend(&config, exporter::build_client(&config).unwrap());
+    let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+    let topology = Arc::new(RwLock::new(Topology::default()));
+    exporter::poll_unifi_data(backend.as_ref(), &metrics, &topology)
+        .await
+        .unwrap();
+
+    let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+    let topology = Arc::new(RwLock::new(Topology::default()));
+    exporter::poll_unifi_data(&ReplayBackend::new(&dir), &metrics, &topology)
+        .await
+        .unwrap();
+    let output = metrics.read().await.gather();
+    std::fs::remove_dir_all(&dir).unwrap();
+    output
+}
+
+fn assert_replayed(output: &str) {
+    for line in [
+        r#"unifi_device_state{id="d1","#,
+        r#"unifi_device_state{id="d2","#,
+        r#"unifi_clients_total{is_guest="false",network="all",type="wireless"} 1"#,
+        r#"unifi_clients_total{is_guest="false",network="all",type="wired"} 1"#,
+        "unifi_sites_total 2",
+    ] {
+        assert!(output.contains(line), "missing {line}:\n{output}");
+    }
+    assert!(!output.contains("unifi_exporter_errors_total{"), "{output}");
+    // Names are redacted in the capture
+    assert!(!output.contains("Office AP"), "{output}");
+}
+
+#[tokio::test]
+async fn test_record_and_replay_with_api_key() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .api_key("test-api-key")
+        .site(default_site())
+        .site(branch_site())
+        .start()
+        .await;
+
+    let output = record_and_replay(&controller, "api-key", &["--api-key", "test-api-key"]).await;
+    assert_replayed(&output);
+}
+
+#[tokio::test]
+async fn test_record_and_replay_integration_api() {
+    let controller = Simulator::builder(Flavor::UnifiOs)
+        .api_key("test-api-key")
+        .site(default_site())
+        .site(branch_site())
+        .start()
+        .await;
+
+    let output = record_and_replay(
+        &controller,
+        "integration",
+        &["--api-key", "test-api-key", "--network-api", "integration"],
+    )
+    .await;
+    assert_replayed(&output);
+}
@@ -0,0 +1,91 @@
+//! Golden-file tests for the `/metrics` exposition.
+//!
+//! Each directory in `tests/fixtures/golden` holds controller responses in the
+//! `--replay-dir` layout (`stat_device.json`, `stat_sta.json`,
+//! `self_sites.json`) and `metrics.prom`, the exposition they must produce.
+//! After an intentional change to metric names, labels or values, regenerate
+//! the golden files and review the diff:
+//!
+//! ```bash
+//! UPDATE_GOLDEN=1 cargo test --test golden_test
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use unifi_network_exporter::backend::ReplayBackend;
+use unifi_network_exporter::exporter;
+use unifi_network_exporter::metrics::Metrics;
+use unifi_network_exporter::topology::Topology;
+
+const GOLDEN_FILE: &str = "metrics.prom";
+
+fn golden_dirs() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden");
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// The exposition after one poll of the responses in `dir`.
+async fn exposition(dir: &Path) -> String {
+    let metrics = Arc::new(RwLock::new(Metrics::new().unwrap()));
+    let topology = Arc::new(RwLock::new(Topology::default()));
+    exporter::poll_unifi_data(&ReplayBackend::new(dir), &metrics, &topology)
+        .await
+        .unwrap();
+    metrics.read().await.gather()
+}
+
+#[tokio::test]
+async fn test_golden_expositions() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let dirs = golden_dirs();
+    assert!(!dirs.is_empty(), "no golden fixtures found");
+
+    let mut mismatches = Vec::new();
+    for dir in &dirs {
+        let actual = exposition(dir).await;
+        let golden = dir.join(GOLDEN_FILE);
+        if update {
+            std::fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&golden)
+            .unwrap_or_else(|e| panic!("failed to read {}: {e}", golden.display()));
+        if actual != expected {
+            mismatches.push(format!(
+                "{}:\n{}",
+                golden.display(),
+                diff(&expected, &actual)
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "exposition differs from the golden files; if the change is intended, \
+         regenerate them with UPDATE_GOLDEN=1\n\n{}",
+        mismatches.join("\n")
+    );
+}
+
+/// Lines only in the expected (`-`) or actual (`+`) exposition.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let removed = expected_lines
+        .iter()
+        .filter(|line| !actual_lines.contains(line))
+        .map(|line| format!("- {line}"));
+    let added = actual_lines
+        .iter()
+        .filter(|line| !expected_lines.contains(line))
+        .map(|line| format!("+ {line}"));
+    removed.chain(added).collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,565 @@
+//! A local UniFi controller for end-to-end tests.
+//!
+//! The simulator speaks enough of the classic and UniFi OS controller APIs,
+//! and of the Integration API, for the exporter to detect the controller
+//! type, log in, and poll devices, clients and sites. It serves a set of sites with configurable devices and
+//! clients, and can inject faults: expiring sessions, slow responses and
+//! malformed JSON.
+
+#![allow(dead_code)]
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Which controller software to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// Self-hosted Network application: `/api/login`, `unifises` cookie
+    Classic,
+    /// UniFi OS console: `/api/auth/login`, `TOKEN` JWT cookie and CSRF
+    /// token, Network API under `/proxy/network`
+    UnifiOs,
+}
+
+/// A site and the devices and clients the controller reports for it.
+#[derive(Debug, Clone)]
+pub struct SimSite {
+    pub name: String,
+    pub desc: String,
+    pub devices: Vec<Value>,
+    pub clients: Vec<Value>,
+}
+
+impl SimSite {
+    pub fn new(name: &str, desc: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            desc: desc.to_string(),
+            devices: Vec::new(),
+            clients: Vec::new(),
+        }
+    }
+
+    pub fn with_device(mut self, device: Value) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    pub fn with_client(mut self, client: Value) -> Self {
+        self.clients.push(client);
+        self
+    }
+}
+
+/// Faults to inject; they can be changed while the simulator runs.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Expire every login session after this many authenticated requests
+    /// since the last login, answering the next request with a 401
+    pub unauthorized_after: Option<usize>,
+    /// Delay every API response by this long
+    pub delay: Option<Duration>,
+    /// Endpoints (`stat/device`, `stat/sta`, `self/sites`) answering with
+    /// truncated JSON
+    pub malformed_json: HashSet<String>,
+}
+
+/// An API device in the shape `stat/device` returns.
+pub fn device(id: &str, mac: &str, device_type: &str, name: &str) -> Value {
+    json!({
+        "_id": id,
+        "mac": mac,
+        "type": device_type,
+        "name": name,
+        "model": "U6LR",
+        "version": "6.6.55",
+        "adopted": true,
+        "state": 1,
+        "uptime": 86400,
+    })
+}
+
+/// A client in the shape `stat/sta` returns, connected to the access point
+/// `ap_mac` or, without one, wired.
+pub fn client(id: &str, mac: &str, hostname: &str, ap_mac: Option<&str>) -> Value {
+    let mut client = json!({
+        "_id": id,
+        "mac": mac,
+        "hostname": hostname,
+        "ip": "192.168.1.100",
+        "is_wired": ap_mac.is_none(),
+        "is_guest": false,
+        "tx_bytes": 1000,
+        "rx_bytes": 2000,
+    });
+    if let Some(ap_mac) = ap_mac {
+        client["ap_mac"] = json!(ap_mac);
+        client["signal"] = json!(-60);
+    }
+    client
+}
+
+pub struct SimulatorBuilder {
+    flavor: Flavor,
+    credentials: Option<(String, String)>,
+    api_key: Option<String>,
+    sites: Vec<SimSite>,
+    faults: Faults,
+}
+
+impl SimulatorBuilder {
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    pub fn site(mut self, site: SimSite) -> Self {
+        self.sites.push(site);
+        self
+    }
+
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    pub async fn start(self) -> Simulator {
+        let state = Arc::new(SimState {
+            flavor: self.flavor,
+            credentials: self.credentials,
+            api_key: self.api_key,
+            sites: self.sites,
+            faults: Mutex::new(self.faults),
+            sessions: Mutex::new(HashMap::new()),
+            logins: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            since_login: AtomicUsize::new(0),
+        });
+        let app = Router::new().fallback(handle).with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        Simulator {
+            addr,
+            state,
+            server,
+        }
+    }
+}
+
+/// A running simulated controller, stopped when dropped.
+pub struct Simulator {
+    addr: SocketAddr,
+    state: Arc<SimState>,
+    server: JoinHandle<()>,
+}
+
+impl Simulator {
+    pub fn builder(flavor: Flavor) -> SimulatorBuilder {
+        SimulatorBuilder {
+            flavor,
+            credentials: None,
+            api_key: None,
+            sites: Vec::new(),
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Number of successful logins.
+    pub fn logins(&self) -> usize {
+        self.state.logins.load(Ordering::SeqCst)
+    }
+
+    /// Number of API requests received, whether authorized or not.
+    pub fn requests(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    /// Number of login sessions currently valid.
+    pub fn sessions(&self) -> usize {
+        self.state.sessions.lock().unwrap().len()
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        *self.state.faults.lock().unwrap() = faults;
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+struct SimState {
+    flavor: Flavor,
+    credentials: Option<(String, String)>,
+    api_key: Option<String>,
+    sites: Vec<SimSite>,
+    faults: Mutex<Faults>,
+    /// Session cookie value to the CSRF token issued with it
+    sessions: Mutex<HashMap<String, String>>,
+    logins: AtomicUsize,
+    requests: AtomicUsize,
+    /// Authenticated requests since sessions last expired
+    since_login: AtomicUsize,
+}
+
+impl SimState {
+    fn session_cookie(&self) -> &'static str {
+        match self.flavor {
+            Flavor::Classic => "unifises",
+            Flavor::UnifiOs => "TOKEN",
+        }
+    }
+
+    /// Strip the prefix UniFi OS serves the Network application under.
+    fn network_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match self.flavor {
+            Flavor::Classic => Some(path),
+            Flavor::UnifiOs => path.strip_prefix("/proxy/network"),
+        }
+    }
+}
+
+async fn handle(
+    State(state): State<Arc<SimState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path = uri.path();
+    let (login_path, logout_path) = match state.flavor {
+        Flavor::Classic => ("/api/login", "/api/logout"),
+        Flavor::UnifiOs => ("/api/auth/login", "/api/auth/logout"),
+    };
+
+    if method == Method::POST && path == login_path {
+        return login(&state, &body);
+    }
+    if method == Method::POST && path == logout_path {
+        if let Some(token) = cookie(&headers, state.session_cookie()) {
+            state.sessions.lock().unwrap().remove(&token);
+        }
+        return legacy(Vec::new());
+    }
+    let Some(path) = state.network_path(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if path == "/status" {
+        return Json(json!({"meta": {"rc": "ok", "up": true}, "data": []})).into_response();
+    }
+
+    state.requests.fetch_add(1, Ordering::SeqCst);
+    if let Err(status) = authorize(&state, &headers) {
+        return status.into_response();
+    }
+
+    let faults = state.faults.lock().unwrap().clone();
+    if let Some(delay) = faults.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    if let Some(rest) = path.strip_prefix("/integration/v1/") {
+        return integration(&state, rest, uri.query().unwrap_or_default());
+    }
+    let endpoint = match path.strip_prefix("/api/s/") {
+        Some(rest) => {
+            let Some((site, endpoint)) = rest.split_once('/') else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let Some(site) = state.sites.iter().find(|s| s.name == site) else {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"meta": {"rc": "error", "msg": "api.err.NoSiteContext"}, "data": []})),
+                )
+                    .into_response();
+            };
+            match endpoint {
+                "stat/device" => ("stat/device", site.devices.clone()),
+                "stat/sta" => ("stat/sta", site.clients.clone()),
+                _ => return StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        None if path == "/api/self/sites" => ("self/sites", sites(&state)),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if faults.malformed_json.contains(endpoint.0) {
+        return (
+            [(header::CONTENT_TYPE, "application/json")],
+            r#"{"meta":{"rc":"ok"},"data":[{"_id":"#,
+        )
+            .into_response();
+    }
+    legacy(endpoint.1)
+}
+
+fn login(state: &SimState, body: &[u8]) -> Response {
+    let request: Value = serde_json::from_slice(body).unwrap_or_default();
+    let accepted = state
+        .credentials
+        .as_ref()
+        .is_some_and(|(username, password)| {
+            request["username"] == username.as_str() && request["password"] == password.as_str()
+        });
+    if !accepted {
+        return match state.flavor {
+            Flavor::Classic => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"meta": {"rc": "error", "msg": "api.err.Invalid"}, "data": []})),
+            )
+                .into_response(),
+            Flavor::UnifiOs => StatusCode::UNAUTHORIZED.into_response(),
+        };
+    }
+
+    let logins = state.logins.fetch_add(1, Ordering::SeqCst) + 1;
+    state.since_login.store(0, Ordering::SeqCst);
+    let csrf = format!("csrf-{logins}");
+    let token = match state.flavor {
+        Flavor::Classic => format!("session-{logins}"),
+        Flavor::UnifiOs => jwt(&csrf),
+    };
+    state
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(token.clone(), csrf.clone());
+
+    let mut response = legacy(Vec::new());
+    let headers = response.headers_mut();
+    let cookie = format!("{}={token}; Path=/; HttpOnly", state.session_cookie());
+    headers.append(header::SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+    match state.flavor {
+        Flavor::Classic => {
+            let cookie = format!("csrf_token={csrf}; Path=/");
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        }
+        Flavor::UnifiOs => {
+            headers.insert("x-csrf-token", HeaderValue::from_str(&csrf).unwrap());
+        }
+    }
+    response
+}
+
+/// Check the API key or session of a request.
+///
+/// UniFi OS sessions must send the CSRF token issued at login. The real
+/// console only checks it on writes; checking every request catches a
+/// client that forgets it.
+fn authorize(state: &SimState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let unauthorized = || Err(StatusCode::UNAUTHORIZED);
+
+    if let Some(key) = headers.get("x-api-key") {
+        return match &state.api_key {
+            Some(expected) if key.as_bytes() == expected.as_bytes() => Ok(()),
+            _ => unauthorized(),
+        };
+    }
+
+    let Some(token) = cookie(headers, state.session_cookie()) else {
+        return unauthorized();
+    };
+    let mut sessions = state.sessions.lock().unwrap();
+    let Some(csrf) = sessions.get(&token) else {
+        return unauthorized();
+    };
+    if state.flavor == Flavor::UnifiOs
+        && headers.get("x-csrf-token").map(HeaderValue::as_bytes) != Some(csrf.as_bytes())
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let count = state.since_login.fetch_add(1, Ordering::SeqCst) + 1;
+    if let Some(limit) = state.faults.lock().unwrap().unauthorized_after
+        && count > limit
+    {
+        sessions.clear();
+        return unauthorized();
+    }
+    Ok(())
+}
+
+/// The Integration API v1 lists of sites, devices and clients, and device
+/// details and statistics, converted from the classic API shapes.
+fn integration(state: &SimState, path: &str, query: &str) -> Response {
+    let segments: Vec<&str> = path.split('/').collect();
+    let (site, rest) = match segments.as_slice() {
+        ["sites"] => {
+            let sites = state
+                .sites
+                .iter()
+                .enumerate()
+                .map(|(i, site)| {
+                    json!({
+                        "id": integration_site_id(i),
+                        "internalReference": site.name,
+                        "name": site.desc,
+                    })
+                })
+                .collect();
+            return paged(sites, query);
+        }
+        ["sites", id, rest @ ..] => {
+            let site = (0..state.sites.len())
+                .find(|&i| integration_site_id(i) == *id)
+                .map(|i| &state.sites[i]);
+            let Some(site) = site else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            (site, rest)
+        }
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let find_device = |id: &str| site.devices.iter().find(|device| device["_id"] == id);
+    match rest {
+        ["devices"] => paged(site.devices.iter().map(integration_device).collect(), query),
+        ["devices", id] => match find_device(id) {
+            Some(device) => Json(integration_device(device)).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        ["devices", id, "statistics", "latest"] => match find_device(id) {
+            Some(device) => Json(json!({"uptimeSec": device["uptime"]})).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        ["clients"] => {
+            let clients = site
+                .clients
+                .iter()
+                .map(|client| integration_client(site, client))
+                .collect();
+            paged(clients, query)
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn integration_site_id(index: usize) -> String {
+    format!("00000000-0000-0000-0000-{index:012}")
+}
+
+fn integration_device(device: &Value) -> Value {
+    let features: &[&str] = match device["type"].as_str() {
+        Some("uap") => &["accessPoint"],
+        Some("usw") => &["switching"],
+        _ => &[],
+    };
+    json!({
+        "id": device["_id"],
+        "name": device["name"],
+        "model": device["model"],
+        "macAddress": device["mac"],
+        "state": if device["state"] == 1 { "ONLINE" } else { "OFFLINE" },
+        "firmwareVersion": device["version"],
+        "features": features,
+    })
+}
+
+fn integration_client(site: &SimSite, client: &Value) -> Value {
+    let wired = client["is_wired"] == true;
+    let uplink = if wired {
+        &client["sw_mac"]
+    } else {
+        &client["ap_mac"]
+    };
+    let uplink_device_id = site
+        .devices
+        .iter()
+        .find(|device| device["mac"] == *uplink)
+        .map(|device| device["_id"].clone());
+    json!({
+        "id": client["_id"],
+        "name": client["hostname"],
+        "type": if wired { "WIRED" } else { "WIRELESS" },
+        "macAddress": client["mac"],
+        "ipAddress": client["ip"],
+        "uplinkDeviceId": uplink_device_id,
+    })
+}
+
+/// One page of an Integration API list, as `offset` and `limit` select.
+fn paged(items: Vec<Value>, query: &str) -> Response {
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+    };
+    let offset = param("offset").unwrap_or(0);
+    let limit = param("limit").unwrap_or(25);
+    let total = items.len();
+    let page: Vec<Value> = items.into_iter().skip(offset).take(limit).collect();
+    Json(json!({
+        "offset": offset,
+        "limit": limit,
+        "count": page.len(),
+        "totalCount": total,
+        "data": page,
+    }))
+    .into_response()
+}
+
+fn sites(state: &SimState) -> Vec<Value> {
+    state
+        .sites
+        .iter()
+        .enumerate()
+        .map(|(i, site)| json!({"_id": format!("site{i}"), "name": site.name, "desc": site.desc}))
+        .collect()
+}
+
+/// A response in the legacy API envelope.
+fn legacy(data: Vec<Value>) -> Response {
+    Json(json!({"meta": {"rc": "ok"}, "data": data})).into_response()
+}
+
+fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// An unsigned UniFi OS style JWT valid for an hour.
+fn jwt(csrf: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = json!({"exp": now + 3600, "csrfToken": csrf});
+    format!(
+        "eyJhbGciOiJIUzI1NiJ9.{}.signature",
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    )
+}